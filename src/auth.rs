@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::config::Config;
+use crate::path;
+use crate::utils::glob_match;
+
+// checks the credentials presented in a client's handshake and decides what the client may access
+//
+// a client whose name has a token configured must present that token,
+// every other client must present the server password if one is set
+pub struct Auth {
+    password: Option<String>,
+    tokens: HashMap<String, String>,
+    clients: HashMap<String, Access>,
+    max_attempts: u32,
+    lockout: Duration,
+    // failed attempts per address, used to slow down password guessing
+    failures: Mutex<HashMap<IpAddr, Failures>>,
+}
+
+struct Failures {
+    count: u32,
+    locked_until: Option<Instant>,
+}
+
+// the keys a client may read and write, as glob patterns
+// None allows every key and an empty list allows none
+#[derive(Clone, Default)]
+pub struct Access {
+    pub read: Option<Vec<String>>,
+    pub write: Option<Vec<String>>,
+}
+
+impl Access {
+    pub fn can_read(&self, key: &str) -> bool {
+        allows(&self.read, key)
+    }
+
+    pub fn can_write(&self, key: &str) -> bool {
+        allows(&self.write, key)
+    }
+
+    // the patterns as sent to clients in the handshake, ["*"] when unrestricted
+    pub fn to_json(&self) -> serde_json::Value {
+        let patterns = |patterns: &Option<Vec<String>>| match patterns {
+            Some(patterns) => patterns.clone(),
+            None => vec!["*".to_string()],
+        };
+        serde_json::json!({
+            "read": patterns(&self.read),
+            "write": patterns(&self.write),
+        })
+    }
+}
+
+// a pattern matching an object also covers every path inside it
+fn allows(patterns: &Option<Vec<String>>, key: &str) -> bool {
+    match patterns {
+        Some(patterns) => patterns.iter().any(|pattern| path::ancestors(key).any(|key| glob_match(pattern, key))),
+        None => true,
+    }
+}
+
+pub enum AuthError {
+    Failed,
+    // too many failed attempts, with the time left before the address may try again
+    LockedOut(Duration),
+}
+
+impl AuthError {
+    pub fn message(&self) -> String {
+        match self {
+            AuthError::Failed => "Authentication failed".to_string(),
+            AuthError::LockedOut(remaining) => format!(
+                "Too many failed attempts, try again in {} seconds",
+                remaining.as_secs() + 1
+            ),
+        }
+    }
+}
+
+impl Auth {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            password: config.password.clone(),
+            tokens: config.clients.iter()
+                .filter_map(|(name, client)| client.token.clone().map(|token| (name.clone(), token)))
+                .collect(),
+            clients: config.clients.iter()
+                .map(|(name, client)| (name.clone(), Access { read: client.read.clone(), write: client.write.clone() }))
+                .collect(),
+            max_attempts: config.auth_max_attempts,
+            lockout: Duration::from_secs(config.auth_lockout),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // returns what the client may access if its credentials are accepted
+    pub fn check(&self, address: IpAddr, name: &str, password: Option<&str>, token: Option<&str>) -> Result<Access, AuthError> {
+        let mut failures = self.failures.lock().unwrap();
+
+        if let Some(failure) = failures.get(&address) {
+            if let Some(locked_until) = failure.locked_until {
+                let now = Instant::now();
+                if locked_until > now {
+                    return Err(AuthError::LockedOut(locked_until - now));
+                }
+                failures.remove(&address);
+            }
+        }
+
+        let accepted = match (self.tokens.get(name), &self.password) {
+            (Some(expected), _) => token.is_some_and(|token| constant_time_eq(token, expected)),
+            (None, Some(expected)) => password.is_some_and(|password| constant_time_eq(password, expected)),
+            (None, None) => true,
+        };
+
+        if accepted {
+            failures.remove(&address);
+            return Ok(self.access(name));
+        }
+
+        let failure = failures.entry(address).or_insert(Failures { count: 0, locked_until: None });
+        failure.count += 1;
+        if failure.count >= self.max_attempts {
+            failure.locked_until = Some(Instant::now() + self.lockout);
+        }
+
+        Err(AuthError::Failed)
+    }
+
+    // clients without their own entry fall back to the "*" entry, then to full access
+    pub fn access(&self, name: &str) -> Access {
+        match self.clients.get(name).or_else(|| self.clients.get("*")) {
+            Some(access) => access.clone(),
+            None => Access::default(),
+        }
+    }
+}
+
+// compare secrets without returning early on the first mismatched byte
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
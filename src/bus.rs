@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use serde_json::Value;
+use crate::auth::Access;
+use crate::connection::Outbox;
+use crate::path::{self, SEPARATOR};
+use crate::protocol;
+use crate::utils::glob_match;
+
+// fans accepted updates out to every connected client
+//
+// publishing pushes the message straight onto each subscriber's outgoing queue, which only that
+// subscriber's connection thread drains, so every subscriber gets each message exactly once
+// publishers hold the table lock while publishing, so messages are queued in the order they were applied
+#[derive(Default)]
+pub struct Bus {
+    subscribers: Mutex<BTreeMap<usize, Subscriber>>,
+}
+
+struct Subscriber {
+    queue: Arc<Outbox>,
+    access: Access,
+    // glob patterns of the keys the subscriber wants, None for every key
+    keys: Option<Vec<String>>,
+}
+
+impl Bus {
+    pub fn subscribe(&self, id: usize, queue: Arc<Outbox>, access: Access) {
+        self.subscribers.lock().unwrap().insert(id, Subscriber { queue, access, keys: None });
+    }
+
+    pub fn set_keys(&self, id: usize, keys: Option<Vec<String>>) {
+        if let Some(subscriber) = self.subscribers.lock().unwrap().get_mut(&id) {
+            subscriber.keys = keys;
+        }
+    }
+
+    pub fn unsubscribe(&self, id: usize) {
+        self.subscribers.lock().unwrap().remove(&id);
+    }
+
+    // deliver a message to every subscriber except the one it came from, None for the console
+    pub fn publish(&self, from: Option<usize>, message: &Value) {
+        for (id, subscriber) in self.subscribers.lock().unwrap().iter() {
+            if Some(*id) == from {
+                continue;
+            }
+            if let Some(message) = subscriber.filter(message.clone()) {
+                subscriber.queue.push(message);
+            }
+        }
+    }
+}
+
+impl Subscriber {
+    // strip the keys a client isn't allowed to read or hasn't subscribed to from an outgoing update or deletion,
+    // dropping the message entirely if nothing is left or if it is a patch of such a key
+    //
+    // a client subscribed to a path inside a changed key gets the value at its path rather than missing the change,
+    // or the whole value if it subscribed with a pattern that can't be followed into it
+    fn filter(&self, mut message: Value) -> Option<Value> {
+        // a subscriber that only wants paths inside a patched key gets the update the patch amounts to,
+        // the patched value is stripped from the patch itself once it is known whether the client can apply it
+        if let Some(Value::String(key)) = message.get("key").cloned() {
+            if self.access.can_read(&key) && self.wants(&key) {
+                return Some(message);
+            }
+            message = match self.inside(&key).next() {
+                Some(_) => protocol::patched(&message)?,
+                None => return None,
+            };
+        }
+        if let Some(Value::Object(table)) = message.get_mut("table") {
+            for (key, value) in std::mem::take(table) {
+                if self.access.can_read(&key) && self.wants(&key) {
+                    table.insert(key, value);
+                    continue;
+                }
+                for pattern in self.inside(&key) {
+                    match follow(&key, pattern, &value) {
+                        Some(value) if self.access.can_read(pattern) => {
+                            table.insert(pattern.clone(), value.clone());
+                        }
+                        None if glob(pattern) && self.access.can_read(&key) => {
+                            table.insert(key.clone(), value.clone());
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            if table.is_empty() {
+                return None;
+            }
+        }
+        for field in ["schema", "versions"] {
+            if let Some(Value::Object(table)) = message.get_mut(field) {
+                table.retain(|key, _| self.access.can_read(key) && self.wants(key) || self.inside(key).any(|pattern| self.access.can_read(pattern)));
+                if table.is_empty() {
+                    return None;
+                }
+            }
+        }
+        if let Some(Value::Array(keys)) = message.get_mut("keys") {
+            let mut deleted = Vec::new();
+            for key in std::mem::take(keys).iter().filter_map(|key| key.as_str()) {
+                if self.access.can_read(key) && self.wants(key) {
+                    deleted.push(key.to_string());
+                    continue;
+                }
+                // deleting a key deletes every path inside it
+                for pattern in self.inside(key).filter(|pattern| self.access.can_read(pattern)) {
+                    let deleted_key = if glob(pattern) { key.to_string() } else { pattern.clone() };
+                    if !deleted.contains(&deleted_key) {
+                        deleted.push(deleted_key);
+                    }
+                }
+            }
+            if deleted.is_empty() {
+                return None;
+            }
+            *keys = deleted.into_iter().map(Value::String).collect();
+        }
+        Some(message)
+    }
+
+    fn wants(&self, key: &str) -> bool {
+        match &self.keys {
+            Some(keys) => keys.iter().any(|pattern| path::ancestors(key).any(|key| glob_match(pattern, key))),
+            None => true,
+        }
+    }
+
+    // the subscribed patterns pointing somewhere inside the key
+    fn inside<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a String> {
+        self.keys.iter().flatten()
+            .filter(move |pattern| pattern.strip_prefix(key).is_some_and(|rest| rest.starts_with(SEPARATOR)))
+    }
+}
+
+fn glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+// the value at a path inside the value of a key, if the path can be followed
+fn follow<'a>(key: &str, pattern: &str, value: &'a Value) -> Option<&'a Value> {
+    if glob(pattern) {
+        return None;
+    }
+    pattern[key.len() + 1..].split(SEPARATOR).try_fold(value, |value, field| value.get(field))
+}
@@ -0,0 +1,92 @@
+use std::path::PathBuf;
+use crate::logger::Level;
+
+// only the options given on the command line are set, everything else comes from the config
+pub struct Args {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub ws_port: Option<u16>,
+    pub state: Option<PathBuf>,
+    pub config: Option<PathBuf>,
+    pub console: Option<bool>,
+    pub log_level: Option<Level>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub help: bool,
+}
+
+pub fn print_help() {
+    println!("Usage: socketboard [options]\n");
+    println!("Options:");
+    println!("      --host <address>     Address to listen on (default: 127.0.0.1)");
+    println!("  -p, --port <port>        TCP port to listen on (default: 8080)");
+    println!("      --ws-port <port>     WebSocket and dashboard port (default: the TCP port + 1)");
+    println!("  -s, --state <file>       Load initial table values from a JSON file");
+    println!("  -c, --config <file>      Load settings from a TOML config file, other options override it");
+    println!("      --no-console         Run without the interactive console");
+    println!("      --log-level <level>  One of off, error, warn, info, debug (default: info)");
+    println!("      --tls-cert <file>    Serve TLS on both listeners with this PEM certificate chain");
+    println!("      --tls-key <file>     PEM private key for --tls-cert");
+    println!("  -h, --help               Display this help message");
+}
+
+// parse the process arguments, not including the program name
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args {
+        host: None,
+        port: None,
+        ws_port: None,
+        state: None,
+        config: None,
+        console: None,
+        log_level: None,
+        tls_cert: None,
+        tls_key: None,
+        help: false,
+    };
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        // allow --flag=value as well as --flag value
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+            _ => (arg.clone(), None),
+        };
+
+        let mut value = |name: &str| -> Result<String, String> {
+            match inline_value.clone().or_else(|| args.next()) {
+                Some(value) => Ok(value),
+                None => Err(format!("Missing value for {}", name)),
+            }
+        };
+
+        match flag.as_str() {
+            "--host" => parsed.host = Some(value("--host")?),
+            "-p" | "--port" => parsed.port = Some(parse_port("--port", &value("--port")?)?),
+            "--ws-port" => parsed.ws_port = Some(parse_port("--ws-port", &value("--ws-port")?)?),
+            "-s" | "--state" => parsed.state = Some(PathBuf::from(value("--state")?)),
+            "-c" | "--config" => parsed.config = Some(PathBuf::from(value("--config")?)),
+            "--no-console" => parsed.console = Some(false),
+            "--log-level" => {
+                let level = value("--log-level")?;
+                parsed.log_level = match Level::from_name(&level) {
+                    Some(level) => Some(level),
+                    None => return Err(format!("Invalid log level: {} (expected off, error, warn, info or debug)", level)),
+                };
+            }
+            "--tls-cert" => parsed.tls_cert = Some(PathBuf::from(value("--tls-cert")?)),
+            "--tls-key" => parsed.tls_key = Some(PathBuf::from(value("--tls-key")?)),
+            "-h" | "--help" => parsed.help = true,
+            _ => return Err(format!("Unknown argument: {}", arg)),
+        }
+    }
+
+    Ok(parsed)
+}
+
+fn parse_port(name: &str, value: &str) -> Result<u16, String> {
+    match value.parse::<u16>() {
+        Ok(port) => Ok(port),
+        Err(_) => Err(format!("Invalid value for {}: {} (expected a port number between 0 and 65535)", name, value)),
+    }
+}
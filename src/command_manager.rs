@@ -1,9 +1,13 @@
 use std::collections::HashMap;
 use std::io::Write;
-use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use crate::bus::Bus;
+use crate::config::Config;
 use crate::connection::Connection;
+use crate::journal::Journal;
+use crate::schema::Schemas;
 use crate::utils::Data;
+use crate::versions::Versions;
 
 type ExecFn = Arc<dyn Fn(&ParsedCommand, &CommandManager)>;
 type HelpFn = Arc<dyn Fn()>;
@@ -69,21 +73,33 @@ pub struct CommandManager {
     command_map: HashMap<String, Command>,
     pub table: Arc<Mutex<HashMap<String, Data>>>,
     pub connections: Arc<Mutex<HashMap<usize, Connection>>>,
-    pub address: SocketAddr,
+    pub config: Arc<Config>,
+    pub journal: Option<Arc<Journal>>,
+    pub bus: Arc<Bus>,
+    pub schemas: Arc<Schemas>,
+    pub versions: Arc<Versions>,
 }
 
 impl CommandManager {
     pub fn new(
         table: &Arc<Mutex<HashMap<String, Data>>>,
         connections: &Arc<Mutex<HashMap<usize, Connection>>>,
-        socket_addr: SocketAddr,
+        config: &Arc<Config>,
+        journal: &Option<Arc<Journal>>,
+        bus: &Arc<Bus>,
+        schemas: &Arc<Schemas>,
+        versions: &Arc<Versions>,
     ) -> Self {
         Self {
             command_map: HashMap::new(),
             commands: HashMap::new(),
             table: table.clone(),
             connections: connections.clone(),
-            address: socket_addr,
+            config: config.clone(),
+            journal: journal.clone(),
+            bus: bus.clone(),
+            schemas: schemas.clone(),
+            versions: versions.clone(),
         }
     }
 
@@ -134,6 +150,7 @@ impl CommandManager {
         input
     }
 
+    #[allow(dead_code)]
     pub fn no_help() {
         println!("No help available for this command");
     }
@@ -141,10 +158,7 @@ impl CommandManager {
 
 fn parse_command(input: &str) -> Result<ParsedCommand, String> {
     // loop over the input string
-    let tokenized = match tokenize(input) {
-        Ok(t) => t,
-        Err(e) => return Err(e),
-    };
+    let tokenized = tokenize(input)?;
 
     // if there are no tokens, return an empty ParsedCommand
     if tokenized.is_empty() {
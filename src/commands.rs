@@ -1,16 +1,22 @@
 use std::cmp::max;
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
+use serde_json::Value;
 use crate::command_manager::{Command, CommandManager, ParsedCommand};
-use crate::utils;
+use crate::journal;
+use crate::path;
+use crate::persistence;
+use crate::utils::{self, Data, JSON};
 
 pub fn help() -> Command {
     Command::new(
         "help",
         "Display help information",
         Arc::new(|command: &ParsedCommand, commands: &CommandManager| {
-            if command.args.len() == 0 {
+            if command.args.is_empty() {
                 println!("Socketboard server commands:\n");
-                for (_, cmd) in &commands.commands {
+                for cmd in commands.commands.values() {
                     // pad the name out to 20 characters
                     let mut names = cmd.name.to_string() + ", " + &cmd.aliases.join(", ");
                     if cmd.aliases.is_empty() {
@@ -42,7 +48,12 @@ pub fn status() -> Command {
         "display",
         "Display the server information",
         Arc::new(|_: &ParsedCommand, server: &CommandManager| {
-            println!("Address: {}", server.address);
+            if server.config.tcp {
+                println!("Address: {}", server.config.address);
+            }
+            if server.config.websocket {
+                println!("WebSocket: {}", server.config.ws_address);
+            }
             println!("Connections: {}", server.connections.lock().unwrap().len());
             println!("Table: {}", server.table.lock().unwrap().len());
         }),
@@ -59,13 +70,13 @@ pub fn table() -> Command {
         "Display the table",
         Arc::new(|_: &ParsedCommand, server: &CommandManager| {
             let table = server.table.lock().unwrap();
-            if table.len() == 0 {
+            if table.is_empty() {
                 println!("No data in the table");
             } else {
                 println!("Table: ({})", table.len());
             }
             for (key, value) in &*table {
-                let padding = max(20 - key.len(), 3);
+                let padding = max(20_usize.saturating_sub(key.len()), 3);
                 let padding = " ".repeat(padding);
                 println!("{}{}{}", key, padding, value);
             }
@@ -96,12 +107,12 @@ pub fn connections() -> Command {
         "Display the connections",
         Arc::new(|_: &ParsedCommand, server: &CommandManager| {
             let connections = server.connections.lock().unwrap();
-            if connections.len() == 0 {
+            if connections.is_empty() {
                 println!("No connections");
             } else {
                 println!("Connections: ({})", connections.len());
             }
-            for (_, connection) in &*connections {
+            for connection in connections.values() {
                 connection.display();
             }
         }),
@@ -117,7 +128,7 @@ pub fn terminate() -> Command {
         "Terminate a connection",
         Arc::new(|command: &ParsedCommand, server: &CommandManager| {
             // check if the id is provided
-            let string_id = match command.args.get(0) {
+            let string_id = match command.args.first() {
                 Some(id) => {
                     id
                 }
@@ -127,26 +138,19 @@ pub fn terminate() -> Command {
                 }
             };
 
-            let id;
-            match string_id.parse::<usize>() {
-                Ok(i) => {
-                    id = i;
-                }
+            let id = match string_id.parse::<usize>() {
+                Ok(i) => i,
                 Err(e) => {
                     println!("Invalid id: {}", e);
                     return;
                 }
-            }
+            };
 
-            let connections = server.connections.lock().unwrap();
-            let connection = connections.get(&id);
+            let mut connections = server.connections.lock().unwrap();
+            let connection = connections.get_mut(&id);
 
             if let Some(connection) = connection {
-                connection.to_client.lock().unwrap().push(
-                    serde_json::json!({
-                        "terminate": true
-                    })
-                );
+                connection.terminate();
             } else {
                 println!("Connection not found: {}", id);
             }
@@ -157,11 +161,402 @@ pub fn terminate() -> Command {
     )
 }
 
+pub fn config() -> Command {
+    Command::new(
+        "config",
+        "Display the effective configuration",
+        Arc::new(|_: &ParsedCommand, server: &CommandManager| {
+            println!("{}", server.config);
+        }),
+        Arc::new(|| {
+            println!("config - Display the configuration the server is running with, after command-line overrides");
+        }),
+    )
+}
+
+pub fn save() -> Command {
+    Command::new(
+        "save",
+        "Save the table to disk",
+        Arc::new(|command: &ParsedCommand, server: &CommandManager| {
+            let path = match command.args.first() {
+                Some(path) => PathBuf::from(path),
+                None => match &server.config.persistence {
+                    Some(path) => path.clone(),
+                    None => {
+                        println!("No persistence path configured, provide a file");
+                        return;
+                    }
+                },
+            };
+
+            match persistence::save(&server.table, &path) {
+                Ok(count) => println!("Saved {} keys to {}", count, path.display()),
+                Err(e) => println!("Failed to save table to {}: {}", path.display(), e),
+            }
+        }),
+        Arc::new(|| {
+            println!("save (file) - Save the table as JSON, to the configured persistence path if no file is given");
+        }),
+    )
+}
+
+pub fn load() -> Command {
+    Command::new(
+        "load",
+        "Load table values from a file",
+        Arc::new(|command: &ParsedCommand, server: &CommandManager| {
+            let path = match command.args.first() {
+                Some(path) => PathBuf::from(path),
+                None => {
+                    println!("Missing file");
+                    return;
+                }
+            };
+
+            let values = match persistence::read(&path) {
+                Ok(values) => values,
+                Err(e) => {
+                    println!("Failed to load {}: {}", path.display(), e);
+                    return;
+                }
+            };
+
+            let mut table = server.table.lock().unwrap();
+            // like set, nothing is loaded if any value doesn't match its schema
+            if !check_schemas(server, values.iter().map(|(key, value)| (key, value.clone()))) {
+                println!("Nothing loaded from {}", path.display());
+                return;
+            }
+            for (key, value) in &values {
+                table.insert(key.clone(), Data::from_json(value));
+                server.versions.touch(&table, key, None);
+                if let Some(journal) = &server.journal {
+                    journal.record(None, "console", key, value);
+                }
+            }
+            println!("Loaded {} keys from {}", values.len(), path.display());
+
+            // let connected clients know about the new values
+            server.bus.publish(None, &serde_json::json!({
+                "type": "update",
+                "status": "ok",
+                "versions": server.versions.to_json(&table, values.keys()),
+                "table": Value::Object(values)
+            }));
+        }),
+        Arc::new(|| {
+            println!("load [file] - Load values from a JSON object into the table, replacing existing keys");
+        }),
+    )
+}
+
+// print why any of the values about to replace top-level keys don't match their schemas, false if one doesn't
+fn check_schemas<'a>(server: &CommandManager, values: impl Iterator<Item = (&'a String, Value)>) -> bool {
+    let errors = server.schemas.check_all(values);
+    for e in &errors {
+        println!("{}", e);
+    }
+    errors.is_empty()
+}
+
+pub fn set() -> Command {
+    Command::new(
+        "set",
+        "Set a value in the table",
+        Arc::new(|command: &ParsedCommand, server: &CommandManager| {
+            let (key, value) = match command.args.split_first() {
+                Some((key, value)) if !value.is_empty() => (key, value.join(" ")),
+                _ => {
+                    println!("Usage: set [key] [value]");
+                    return;
+                }
+            };
+            let value = parse_value(&value);
+
+            let mut table = server.table.lock().unwrap();
+            if let Err(e) = server.schemas.check(key, &value) {
+                println!("{}", e);
+                return;
+            }
+            if let Err(e) = path::set(&mut table, key, Data::from_json(&value)) {
+                println!("{}", e);
+                return;
+            }
+            server.versions.touch(&table, key, None);
+            if let Some(journal) = &server.journal {
+                journal.record(None, "console", key, &value);
+            }
+            println!("{} = {}", key, value);
+
+            server.bus.publish(None, &serde_json::json!({
+                "type": "update",
+                "status": "ok",
+                "versions": server.versions.to_json(&table, std::iter::once(key)),
+                "table": { key.clone(): value }
+            }));
+        }),
+        Arc::new(|| {
+            println!("set [key] [value] - Set a value and send it to connected clients");
+            println!("  The value is read as JSON, e.g. 1.5, true, null, [1, 2] or {{\\\"a\\\": 1}}");
+            println!("  Quotes have to be escaped to reach the JSON, anything that isn't valid JSON is stored as a string");
+        }),
+    )
+}
+
+pub fn rename() -> Command {
+    Command::new(
+        "rename",
+        "Rename a key in the table",
+        Arc::new(|command: &ParsedCommand, server: &CommandManager| {
+            let (from, to) = match command.args.as_slice() {
+                [from, to] => (from, to),
+                _ => {
+                    println!("Usage: rename [key] [new key]");
+                    return;
+                }
+            };
+
+            let mut table = server.table.lock().unwrap();
+            if path::get(&table, to).is_some() {
+                println!("Key already exists: {}", to);
+                return;
+            }
+            let value = match path::get(&table, from) {
+                Some(value) => value.to_json(),
+                None => {
+                    println!("No such key: {}", from);
+                    return;
+                }
+            };
+            // check the destination before removing anything so a failed rename leaves the table alone
+            if let Err(e) = path::check_set(&table, to).and_then(|_| server.schemas.check(to, &value)) {
+                println!("{}", e);
+                return;
+            }
+            path::remove(&mut table, from);
+            if let Err(e) = path::set(&mut table, to, Data::from_json(&value)) {
+                // only possible when the destination is inside the value that was just moved
+                path::set(&mut table, from, Data::from_json(&value)).ok();
+                println!("{}", e);
+                return;
+            }
+            server.versions.touch(&table, from, None);
+            server.versions.touch(&table, to, None);
+            if let Some(journal) = &server.journal {
+                journal.record_delete(None, "console", from);
+                journal.record(None, "console", to, &value);
+            }
+            println!("Renamed {} to {}", from, to);
+
+            // clients see the old key deleted and the new one set
+            server.bus.publish(None, &serde_json::json!({
+                "type": "delete",
+                "status": "ok",
+                "keys": [from]
+            }));
+            server.bus.publish(None, &serde_json::json!({
+                "type": "update",
+                "status": "ok",
+                "versions": server.versions.to_json(&table, std::iter::once(to)),
+                "table": { to.clone(): value }
+            }));
+        }),
+        Arc::new(|| {
+            println!("rename [key] [new key] - Move a value to a new key and tell connected clients");
+        }),
+    )
+}
+
+// values typed at the console are JSON when they parse as JSON and strings otherwise
+fn parse_value(text: &str) -> Value {
+    match serde_json::from_str(text) {
+        Ok(value) => value,
+        Err(_) => Value::String(text.to_string()),
+    }
+}
+
+pub fn del() -> Command {
+    Command::with_aliases(
+        "del",
+        vec!["delete", "unset"],
+        "Delete keys from the table",
+        Arc::new(|command: &ParsedCommand, server: &CommandManager| {
+            if command.args.is_empty() {
+                println!("Missing key");
+                return;
+            }
+
+            let mut table = server.table.lock().unwrap();
+            let mut deleted = Vec::new();
+            for key in &command.args {
+                if path::remove(&mut table, key).is_none() {
+                    println!("No such key: {}", key);
+                    continue;
+                }
+                server.versions.touch(&table, key, None);
+                if let Some(journal) = &server.journal {
+                    journal.record_delete(None, "console", key);
+                }
+                deleted.push(key.clone());
+            }
+
+            if !deleted.is_empty() {
+                println!("Deleted {} key{}", deleted.len(), if deleted.len() == 1 { "" } else { "s" });
+                server.bus.publish(None, &serde_json::json!({
+                    "type": "delete",
+                    "status": "ok",
+                    "keys": deleted
+                }));
+            }
+        }),
+        Arc::new(|| {
+            println!("del [key]... - Delete keys from the table and tell connected clients (also: unset)");
+        }),
+    )
+}
+
+pub fn replay() -> Command {
+    Command::new(
+        "replay",
+        "Rebuild the table from the journal",
+        Arc::new(|command: &ParsedCommand, server: &CommandManager| {
+            let path = match command.flags.get("journal") {
+                Some(Some(path)) => PathBuf::from(path),
+                _ => match &server.journal {
+                    Some(journal) => journal.path().to_path_buf(),
+                    None => {
+                        println!("No journal configured, provide one with --journal");
+                        return;
+                    }
+                },
+            };
+
+            let until = match command.flags.get("until") {
+                Some(Some(time)) => match utils::parse_time(time) {
+                    Some(until) => Some(until),
+                    None => {
+                        println!("Invalid time: {}", time);
+                        return;
+                    }
+                },
+                Some(None) => {
+                    println!("Missing time for --until");
+                    return;
+                }
+                None => None,
+            };
+
+            let entries = match journal::read(&path) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    println!("Failed to read journal {}: {}", path.display(), e);
+                    return;
+                }
+            };
+            if let (Some(first), Some(last)) = (entries.first(), entries.last()) {
+                println!("Journal covers {} to {}", utils::format_time(first.time), utils::format_time(last.time));
+            }
+
+            // show who changed what instead of the resulting table
+            if command.flags.contains_key("list") {
+                for entry in entries.iter().filter(|entry| until.is_none_or(|until| entry.time <= until)) {
+                    let client = match entry.client {
+                        Some(id) => format!("{} ({})", entry.name, id),
+                        None => entry.name.clone(),
+                    };
+                    match &entry.value {
+                        Some(value) => println!("{}  {}  {} = {}", utils::format_time(entry.time), client, entry.key, value),
+                        None => println!("{}  {}  {} deleted", utils::format_time(entry.time), client, entry.key),
+                    }
+                }
+                return;
+            }
+
+            let mut table = HashMap::new();
+            let applied = journal::replay(&entries, &mut table, None, until);
+            match until {
+                Some(until) => println!("Replayed {} of {} entries up to {}", applied, entries.len(), utils::format_time(until)),
+                None => println!("Replayed {} entries", applied),
+            }
+
+            if let Some(out) = command.flags.get("out") {
+                let out = match out {
+                    Some(out) => PathBuf::from(out),
+                    None => {
+                        println!("Missing file for --out");
+                        return;
+                    }
+                };
+                match persistence::write_atomic(&out, persistence::snapshot(&table).to_string().as_bytes()) {
+                    Ok(_) => println!("Wrote {} keys to {}", table.len(), out.display()),
+                    Err(e) => println!("Failed to write {}: {}", out.display(), e),
+                }
+            } else if command.flags.contains_key("apply") {
+                // swap in the replayed table, recording the result so the journal stays consistent with it
+                let mut server_table = server.table.lock().unwrap();
+                // schemas may have changed since the journal was written
+                if !check_schemas(server, table.iter().map(|(key, value)| (key, value.to_json()))) {
+                    println!("The table was not replaced");
+                    return;
+                }
+                let deleted: Vec<String> = server_table.keys().filter(|key| !table.contains_key(*key)).cloned().collect();
+                *server_table = table;
+                server.versions.reset(&server_table, None);
+                let values = persistence::snapshot(&server_table);
+                if let (Some(journal), Value::Object(values)) = (&server.journal, &values) {
+                    for key in &deleted {
+                        journal.record_delete(None, "console", key);
+                    }
+                    for (key, value) in values {
+                        journal.record(None, "console", key, value);
+                    }
+                }
+                println!("Replaced the table with {} replayed keys", values.as_object().map_or(0, |values| values.len()));
+                if !deleted.is_empty() {
+                    server.bus.publish(None, &serde_json::json!({
+                        "type": "delete",
+                        "status": "ok",
+                        "keys": deleted
+                    }));
+                }
+                server.bus.publish(None, &serde_json::json!({
+                    "type": "update",
+                    "status": "ok",
+                    "versions": server.versions.to_json(&server_table, server_table.keys()),
+                    "table": values
+                }));
+            } else {
+                let mut keys: Vec<&String> = table.keys().collect();
+                keys.sort();
+                for key in keys {
+                    let padding = max(20 - key.len() as i32, 3) as usize;
+                    println!("{}{}{}", key, " ".repeat(padding), table[key]);
+                }
+            }
+        }),
+        Arc::new(|| {
+            println!("replay [--until time] [--out file | --apply | --list] [--journal file] - Rebuild the table from the journal");
+            println!("  --until    Stop at this time, as milliseconds since the epoch or UTC like 2024-05-01T12:30:00Z");
+            println!("  --out      Write the rebuilt table to a file instead of printing it");
+            println!("  --apply    Replace the live table with the rebuilt one");
+            println!("  --list     List each change with its time and client instead of the rebuilt table");
+            println!("  --journal  Replay a different journal file");
+        }),
+    )
+}
+
 pub fn exit() -> Command {
     Command::new(
         "exit",
         "Exit the program",
-        Arc::new(|_: &ParsedCommand, _: &CommandManager| {
+        Arc::new(|_: &ParsedCommand, server: &CommandManager| {
+            // don't lose anything changed since the last periodic snapshot
+            if let Some(path) = &server.config.persistence {
+                if let Err(e) = persistence::save(&server.table, path) {
+                    println!("Failed to save table to {}: {}", path.display(), e);
+                }
+            }
             std::process::exit(0);
         }),
         Arc::new(|| {
@@ -0,0 +1,438 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use crate::cli::Args;
+use crate::logger::Level;
+use crate::schema::Schema;
+use crate::utils::{Data, JSON};
+
+// settings for a single named client, the "*" entry applies to clients without one of their own
+#[derive(Clone, Default)]
+pub struct ClientConfig {
+    pub token: Option<String>,
+    // glob patterns of keys the client may read and write, None allows every key
+    pub read: Option<Vec<String>>,
+    pub write: Option<Vec<String>>,
+}
+
+// the effective server configuration: defaults, then the config file, then command-line arguments
+#[derive(Clone)]
+pub struct Config {
+    pub file: Option<PathBuf>,
+    pub host: String,
+    pub port: u16,
+    pub ws_port: Option<u16>,
+    pub address: SocketAddr,
+    pub ws_address: SocketAddr,
+    pub tcp: bool,
+    pub websocket: bool,
+    pub console: bool,
+    pub log_level: Level,
+    pub state: Option<PathBuf>,
+    // seconds a client may stay silent before it is pinged, and before it is disconnected, 0 turns either off
+    pub heartbeat: u64,
+    pub idle_timeout: u64,
+    pub persistence: Option<PathBuf>,
+    // seconds between snapshots of the table
+    pub persistence_interval: u64,
+    // append-only log of every table change
+    pub journal: Option<PathBuf>,
+    pub password: Option<String>,
+    // failed handshakes allowed from one address before it is locked out for auth_lockout seconds
+    pub auth_max_attempts: u32,
+    pub auth_lockout: u64,
+    pub clients: BTreeMap<String, ClientConfig>,
+    // certificate chain and private key in PEM format, both listeners serve TLS when set
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    // CA bundle used to verify client certificates, whose common name then identifies the client
+    pub tls_client_ca: Option<PathBuf>,
+    pub tls_require_client_cert: bool,
+    pub table: HashMap<String, Data>,
+    // schemas for keys, which clients can't replace
+    pub schema: BTreeMap<String, Schema>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            file: None,
+            host: "127.0.0.1".to_string(),
+            port: 8080,
+            ws_port: None,
+            address: SocketAddr::from(([127, 0, 0, 1], 8080)),
+            ws_address: SocketAddr::from(([127, 0, 0, 1], 8081)),
+            tcp: true,
+            websocket: true,
+            console: true,
+            log_level: Level::Info,
+            state: None,
+            heartbeat: 10,
+            idle_timeout: 30,
+            persistence: None,
+            persistence_interval: 10,
+            journal: None,
+            password: None,
+            auth_max_attempts: 5,
+            auth_lockout: 60,
+            clients: BTreeMap::new(),
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            tls_require_client_cert: false,
+            table: HashMap::new(),
+            schema: BTreeMap::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn resolve(args: &Args) -> Result<Self, String> {
+        let mut config = Config::default();
+
+        if let Some(path) = &args.config {
+            config.load_file(path)?;
+        }
+
+        // command-line arguments override the file
+        if let Some(host) = &args.host {
+            config.host = host.clone();
+        }
+        if let Some(port) = args.port {
+            config.port = port;
+        }
+        if let Some(ws_port) = args.ws_port {
+            config.ws_port = Some(ws_port);
+        }
+        if let Some(state) = &args.state {
+            config.state = Some(state.clone());
+        }
+        if let Some(console) = args.console {
+            config.console = console;
+        }
+        if let Some(log_level) = args.log_level {
+            config.log_level = log_level;
+        }
+        if let Some(tls_cert) = &args.tls_cert {
+            config.tls_cert = Some(tls_cert.clone());
+        }
+        if let Some(tls_key) = &args.tls_key {
+            config.tls_key = Some(tls_key.clone());
+        }
+
+        config.check_timeouts()?;
+        config.check_tls()?;
+        config.resolve_addresses()?;
+        Ok(config)
+    }
+
+    fn load_file(&mut self, path: &Path) -> Result<(), String> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => return Err(format!("Failed to read config file {}: {}", path.display(), e)),
+        };
+        let file: toml::Table = match contents.parse() {
+            Ok(file) => file,
+            Err(e) => return Err(format!("Failed to parse config file {}: {}", path.display(), e)),
+        };
+
+        self.file = Some(path.to_path_buf());
+
+        for (section, value) in &file {
+            let table = match value.as_table() {
+                Some(table) => table,
+                None => return Err(format!("Config section [{}] must be a table", section)),
+            };
+
+            match section.as_str() {
+                "server" => {
+                    for (key, value) in table {
+                        let name = format!("server.{}", key);
+                        match key.as_str() {
+                            "host" => self.host = get_string(&name, value)?,
+                            "port" => self.port = get_port(&name, value)?,
+                            "ws_port" => self.ws_port = Some(get_port(&name, value)?),
+                            "console" => self.console = get_bool(&name, value)?,
+                            "log_level" => {
+                                let level = get_string(&name, value)?;
+                                self.log_level = match Level::from_name(&level) {
+                                    Some(level) => level,
+                                    None => return Err(format!("Invalid value for {}: {}", name, level)),
+                                };
+                            }
+                            "state" => self.state = Some(relative_to(path, &get_string(&name, value)?)),
+                            "heartbeat" => self.heartbeat = get_seconds(&name, value)?,
+                            "idle_timeout" => self.idle_timeout = get_seconds(&name, value)?,
+                            _ => return Err(format!("Unknown config key: {}", name)),
+                        }
+                    }
+                }
+                "transports" => {
+                    for (key, value) in table {
+                        let name = format!("transports.{}", key);
+                        match key.as_str() {
+                            "tcp" => self.tcp = get_bool(&name, value)?,
+                            "websocket" => self.websocket = get_bool(&name, value)?,
+                            _ => return Err(format!("Unknown config key: {}", name)),
+                        }
+                    }
+                }
+                "persistence" => {
+                    for (key, value) in table {
+                        let name = format!("persistence.{}", key);
+                        match key.as_str() {
+                            "path" => self.persistence = Some(relative_to(path, &get_string(&name, value)?)),
+                            "journal" => self.journal = Some(relative_to(path, &get_string(&name, value)?)),
+                            "interval" => {
+                                self.persistence_interval = match value.as_integer().filter(|interval| *interval > 0) {
+                                    Some(interval) => interval as u64,
+                                    None => return Err(format!("Invalid value for {}: expected a positive number of seconds", name)),
+                                };
+                            }
+                            _ => return Err(format!("Unknown config key: {}", name)),
+                        }
+                    }
+                }
+                "auth" => {
+                    for (key, value) in table {
+                        let name = format!("auth.{}", key);
+                        match key.as_str() {
+                            "password" => self.password = Some(get_string(&name, value)?),
+                            "max_attempts" => {
+                                self.auth_max_attempts = match value.as_integer().and_then(|attempts| u32::try_from(attempts).ok()).filter(|attempts| *attempts > 0) {
+                                    Some(attempts) => attempts,
+                                    None => return Err(format!("Invalid value for {}: expected a positive number", name)),
+                                };
+                            }
+                            "lockout" => {
+                                self.auth_lockout = match value.as_integer().and_then(|lockout| u64::try_from(lockout).ok()) {
+                                    Some(lockout) => lockout,
+                                    None => return Err(format!("Invalid value for {}: expected a number of seconds", name)),
+                                };
+                            }
+                            _ => return Err(format!("Unknown config key: {}", name)),
+                        }
+                    }
+                }
+                "clients" => {
+                    for (client, value) in table {
+                        // client names are alphanumeric, so "*" can't clash with a real client
+                        if client != "*" && !client.chars().all(|c| c.is_alphanumeric()) {
+                            return Err(format!("Invalid client name in config: [clients.{}]", client));
+                        }
+
+                        let settings = match value.as_table() {
+                            Some(settings) => settings,
+                            None => return Err(format!("Config section [clients.{}] must be a table", client)),
+                        };
+
+                        let mut client_config = ClientConfig::default();
+                        for (key, value) in settings {
+                            let name = format!("clients.{}.{}", client, key);
+                            match key.as_str() {
+                                "token" => client_config.token = Some(get_string(&name, value)?),
+                                "read" => client_config.read = Some(get_strings(&name, value)?),
+                                "write" => client_config.write = Some(get_strings(&name, value)?),
+                                _ => return Err(format!("Unknown config key: {}", name)),
+                            }
+                        }
+                        self.clients.insert(client.clone(), client_config);
+                    }
+                }
+                "tls" => {
+                    for (key, value) in table {
+                        let name = format!("tls.{}", key);
+                        match key.as_str() {
+                            "cert" => self.tls_cert = Some(relative_to(path, &get_string(&name, value)?)),
+                            "key" => self.tls_key = Some(relative_to(path, &get_string(&name, value)?)),
+                            "client_ca" => self.tls_client_ca = Some(relative_to(path, &get_string(&name, value)?)),
+                            "require_client_cert" => self.tls_require_client_cert = get_bool(&name, value)?,
+                            _ => return Err(format!("Unknown config key: {}", name)),
+                        }
+                    }
+                }
+                "table" => {
+                    for (key, value) in table {
+                        self.table.insert(key.clone(), to_data(value));
+                    }
+                }
+                "schema" => {
+                    for (key, value) in table {
+                        let schema = Schema::from_json(&to_data(value).to_json())
+                            .map_err(|e| format!("Invalid schema [schema.\"{}\"]: {}", key, e))?;
+                        self.schema.insert(key.clone(), schema);
+                    }
+                }
+                _ => return Err(format!("Unknown config section: [{}]", section)),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_timeouts(&self) -> Result<(), String> {
+        // a client has to be pinged before it times out, or it never gets the chance to answer
+        if self.heartbeat > 0 && self.idle_timeout > 0 && self.idle_timeout <= self.heartbeat {
+            return Err("server.idle_timeout must be longer than server.heartbeat".to_string());
+        }
+        Ok(())
+    }
+
+    fn check_tls(&self) -> Result<(), String> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(_), None) => return Err("A TLS certificate was given without a key, set tls.key".to_string()),
+            (None, Some(_)) => return Err("A TLS key was given without a certificate, set tls.cert".to_string()),
+            (None, None) if self.tls_client_ca.is_some() => return Err("tls.client_ca requires tls.cert and tls.key".to_string()),
+            _ => {}
+        }
+        if self.tls_require_client_cert && self.tls_client_ca.is_none() {
+            return Err("tls.require_client_cert requires tls.client_ca".to_string());
+        }
+        Ok(())
+    }
+
+    fn resolve_addresses(&mut self) -> Result<(), String> {
+        let ws_port = match self.ws_port {
+            Some(ws_port) => ws_port,
+            None => match self.port.checked_add(1) {
+                Some(ws_port) => ws_port,
+                None => return Err("Port 65535 leaves no room for the WebSocket port, set ws_port".to_string()),
+            },
+        };
+        if self.tcp && self.websocket && ws_port == self.port {
+            return Err(format!("The TCP and WebSocket listeners can't share port {}", self.port));
+        }
+        if !self.tcp && !self.websocket {
+            return Err("At least one transport must be enabled".to_string());
+        }
+
+        let ip = resolve_host(&self.host)?;
+        self.address = SocketAddr::new(ip, self.port);
+        self.ws_address = SocketAddr::new(ip, ws_port);
+        Ok(())
+    }
+}
+
+impl Display for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let file = match &self.file {
+            Some(file) => file.display().to_string(),
+            None => "(none)".to_string(),
+        };
+        let path = |path: &Option<PathBuf>| match path {
+            Some(path) => path.display().to_string(),
+            None => "(none)".to_string(),
+        };
+
+        writeln!(f, "Config file: {}", file)?;
+        writeln!(f, "[server]")?;
+        writeln!(f, "host = {}", self.host)?;
+        writeln!(f, "port = {}", self.address.port())?;
+        writeln!(f, "ws_port = {}", self.ws_address.port())?;
+        writeln!(f, "console = {}", self.console)?;
+        writeln!(f, "log_level = {}", self.log_level.name())?;
+        writeln!(f, "state = {}", path(&self.state))?;
+        writeln!(f, "heartbeat = {}", self.heartbeat)?;
+        writeln!(f, "idle_timeout = {}", self.idle_timeout)?;
+        writeln!(f, "[transports]")?;
+        writeln!(f, "tcp = {}", self.tcp)?;
+        writeln!(f, "websocket = {}", self.websocket)?;
+        writeln!(f, "[persistence]")?;
+        writeln!(f, "path = {}", path(&self.persistence))?;
+        writeln!(f, "interval = {}", self.persistence_interval)?;
+        writeln!(f, "journal = {}", path(&self.journal))?;
+        writeln!(f, "[auth]")?;
+        writeln!(f, "password = {}", if self.password.is_some() { "(set)" } else { "(none)" })?;
+        writeln!(f, "max_attempts = {}", self.auth_max_attempts)?;
+        writeln!(f, "lockout = {}", self.auth_lockout)?;
+        for (name, client) in &self.clients {
+            writeln!(f, "[clients.{}]", name)?;
+            writeln!(f, "token = {}", if client.token.is_some() { "(set)" } else { "(none)" })?;
+            let patterns = |patterns: &Option<Vec<String>>| match patterns {
+                Some(patterns) => format!("[{}]", patterns.join(", ")),
+                None => "(all)".to_string(),
+            };
+            writeln!(f, "read = {}", patterns(&client.read))?;
+            writeln!(f, "write = {}", patterns(&client.write))?;
+        }
+        writeln!(f, "[tls]")?;
+        writeln!(f, "cert = {}", path(&self.tls_cert))?;
+        writeln!(f, "key = {}", path(&self.tls_key))?;
+        writeln!(f, "client_ca = {}", path(&self.tls_client_ca))?;
+        writeln!(f, "require_client_cert = {}", self.tls_require_client_cert)?;
+        writeln!(f, "[schema]\n{} keys", self.schema.len())?;
+        write!(f, "[table]\n{} initial keys", self.table.len())
+    }
+}
+
+fn get_string(name: &str, value: &toml::Value) -> Result<String, String> {
+    match value.as_str() {
+        Some(string) => Ok(string.to_string()),
+        None => Err(format!("Invalid value for {}: expected a string", name)),
+    }
+}
+
+fn get_bool(name: &str, value: &toml::Value) -> Result<bool, String> {
+    match value.as_bool() {
+        Some(boolean) => Ok(boolean),
+        None => Err(format!("Invalid value for {}: expected true or false", name)),
+    }
+}
+
+fn get_port(name: &str, value: &toml::Value) -> Result<u16, String> {
+    match value.as_integer().and_then(|port| u16::try_from(port).ok()) {
+        Some(port) => Ok(port),
+        None => Err(format!("Invalid value for {}: expected a port number between 0 and 65535", name)),
+    }
+}
+
+fn get_seconds(name: &str, value: &toml::Value) -> Result<u64, String> {
+    match value.as_integer().and_then(|seconds| u64::try_from(seconds).ok()) {
+        Some(seconds) => Ok(seconds),
+        None => Err(format!("Invalid value for {}: expected a number of seconds", name)),
+    }
+}
+
+fn get_strings(name: &str, value: &toml::Value) -> Result<Vec<String>, String> {
+    let array = match value.as_array() {
+        Some(array) => array,
+        None => return Err(format!("Invalid value for {}: expected an array of strings", name)),
+    };
+    array.iter().map(|value| get_string(name, value)).collect()
+}
+
+// paths in the config file are relative to the file, not the working directory
+fn relative_to(config: &Path, path: &str) -> PathBuf {
+    match config.parent() {
+        Some(parent) => parent.join(path),
+        None => PathBuf::from(path),
+    }
+}
+
+fn to_data(value: &toml::Value) -> Data {
+    match value {
+        toml::Value::String(s) => Data::String(s.clone()),
+        toml::Value::Integer(i) => Data::Integer(*i),
+        toml::Value::Float(f) => Data::Float(*f),
+        toml::Value::Boolean(b) => Data::Boolean(*b),
+        toml::Value::Datetime(d) => Data::String(d.to_string()),
+        toml::Value::Array(a) => Data::Array(a.iter().map(to_data).collect()),
+        toml::Value::Table(t) => Data::Object(t.iter().map(|(key, value)| (key.clone(), to_data(value))).collect()),
+    }
+}
+
+// accept IP addresses directly and fall back to resolving hostnames like "localhost"
+fn resolve_host(host: &str) -> Result<IpAddr, String> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(ip);
+    }
+
+    match (host, 0).to_socket_addrs() {
+        Ok(mut addresses) => match addresses.next() {
+            Some(address) => Ok(address.ip()),
+            None => Err(format!("Host {} did not resolve to any address", host)),
+        },
+        Err(e) => Err(format!("Invalid host {}: {}", host, e)),
+    }
+}
@@ -1,315 +1,1441 @@
 use std::collections::HashMap;
 use std::io::{Error, ErrorKind, Read, Write};
-use std::net::{Shutdown, TcpStream};
+use std::net::{IpAddr, Shutdown, TcpStream};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use mio::{Events, Interest, Poll, Token, Waker};
+use rustls::ServerConfig;
 use serde_json::{json, Value};
-use crate::utils::{Data, JSON};
+use crate::auth::{Access, Auth, AuthError};
+use crate::bus::Bus;
+use crate::dashboard;
+use crate::framing::{FrameBuffer, Framing, MAX_FRAME_SIZE};
+use crate::http;
+use crate::journal::Journal;
+use crate::logger::{log_debug, log_info, log_warn};
+use crate::patch;
+use crate::protocol::{self, ErrorCode, ProtocolError};
+use crate::path;
+use crate::schema::{Schema, Schemas};
+use crate::stream::{EventedStream, Stream};
+use crate::utils::{glob_match, Data, JSON};
+use crate::versions::Versions;
+use crate::websocket;
+
+// how long a client may leave output unread before it is considered gone
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+// how much output may pile up for a client that reads slower than updates arrive
+const MAX_OUTPUT: usize = 4 * MAX_FRAME_SIZE;
+// how much input may be buffered before its messages are handled without waiting for the socket to be drained
+const PROCESS_THRESHOLD: usize = 1024 * 1024;
+
+const SOCKET: Token = Token(0);
+const WAKER: Token = Token(1);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Transport {
+    Tcp,
+    WebSocket,
+}
+
+// the shared server state every connection thread works with
+#[derive(Clone)]
+pub struct Context {
+    pub table: Arc<Mutex<HashMap<String, Data>>>,
+    pub connections: Arc<Mutex<HashMap<usize, Connection>>>,
+    pub journal: Option<Arc<Journal>>,
+    pub auth: Arc<Auth>,
+    pub bus: Arc<Bus>,
+    pub schemas: Arc<Schemas>,
+    pub versions: Arc<Versions>,
+    // how long a client may stay silent before it is pinged, and before it is disconnected
+    pub heartbeat: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+}
+
+// messages waiting to be written to a client
+// pushing one wakes the client's connection thread so it goes out straight away
+pub struct Outbox {
+    messages: Mutex<Vec<Value>>,
+    waker: Waker,
+}
+
+impl Outbox {
+    pub fn push(&self, message: Value) {
+        self.messages.lock().unwrap().push(message);
+        if let Err(e) = self.waker.wake() {
+            log_warn!("Failed to wake connection: {}", e);
+        }
+    }
+    
+    fn take(&self) -> Vec<Value> {
+        std::mem::take(&mut *self.messages.lock().unwrap())
+    }
+}
 
 pub struct Connection {
-    pub to_client: Arc<Mutex<Vec<Value>>>,
-    pub to_server: Arc<Mutex<Vec<Value>>>,
+    pub to_client: Arc<Outbox>,
     name: Arc<Mutex<String>>,
-    id: usize
+    // when the client last sent anything
+    last_seen: Arc<Mutex<Instant>>,
+    id: usize,
+    transport: Transport,
+    tls: bool,
 }
 
 impl Connection {
+    // returns the connection and the poll its thread waits on
     pub fn new(
         id: usize,
-    ) -> Self {
-        Self {
-            to_client: Arc::new(Mutex::new(Vec::new())),
-            to_server: Arc::new(Mutex::new(Vec::new())),
+        transport: Transport,
+        tls: bool,
+    ) -> Result<(Self, Poll), Error> {
+        let poll = Poll::new()?;
+        let waker = Waker::new(poll.registry(), WAKER)?;
+        let connection = Self {
+            to_client: Arc::new(Outbox {
+                messages: Mutex::new(Vec::new()),
+                waker,
+            }),
             name: Arc::new(Mutex::new(String::new())),
-            id
-        }
+            last_seen: Arc::new(Mutex::new(Instant::now())),
+            id,
+            transport,
+            tls,
+        };
+        Ok((connection, poll))
     }
     
     pub fn watch(
         &mut self,
-        stream: &mut TcpStream,
-        table: Arc<Mutex<HashMap<String, Data>>>,
-        connections: Arc<Mutex<HashMap<usize, Connection>>>,
+        socket: TcpStream,
+        mut poll: Poll,
+        tls: Option<Arc<ServerConfig>>,
+        context: Context,
     ) {
-        // new thread
-        let table = table.clone();
-        let connections = connections.clone();
-        let to_client = self.to_client.clone();
-        let to_server = self.to_server.clone();
-        let name = self.name.clone();
-        let id = self.id;
-        let mut stream = stream.try_clone().unwrap();
-        
-        stream.set_nonblocking(true).unwrap();
+        let table = context.table;
+        let connections = context.connections;
+        let heartbeat = context.heartbeat;
+        let idle_timeout = context.idle_timeout;
+        let address = match socket.peer_addr() {
+            Ok(address) => address.ip(),
+            Err(_) => IpAddr::from([0, 0, 0, 0]),
+        };
+        let mut session = Session {
+            id: self.id,
+            address,
+            handshake: false,
+            frames: FrameBuffer::new(),
+            name: self.name.clone(),
+            last_seen: self.last_seen.clone(),
+            to_client: self.to_client.clone(),
+            journal: context.journal,
+            auth: context.auth,
+            bus: context.bus,
+            schemas: context.schemas,
+            versions: context.versions,
+            access: Access::default(),
+            identity: None,
+            subscriptions: None,
+            features: protocol::features(1),
+        };
+        let transport = self.transport;
         
         std::thread::spawn(move || {
-            println!("New connection with id: {}", id);
+            let id = session.id;
+            let _registration = Registration { id, connections, bus: session.bus.clone() };
             
-            let mut handshake = false;
-
-            fn send(json_value: &Value, message_buffer: &Arc<Mutex<Vec<Value>>>) {
-                let mut buffer = message_buffer.lock().unwrap();
-                buffer.push(json_value.clone());
-            }
+            // the TLS handshake happens here rather than on the accept thread so a slow client can't hold up the others
+            let mut stream = match tls {
+                Some(tls) => match Stream::accept_tls(socket, tls) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        log_warn!("Failed TLS handshake ({}): {}", id, e);
+                        return;
+                    }
+                },
+                None => Stream::Plain(socket),
+            };
+            session.identity = stream.peer_identity();
             
-            loop {
-                // read from stream
-                match Connection::read(&mut stream) {
-                    Ok(result) => match result {
-                        Some(json_values) => {
-                            for json in json_values {
-                                match Connection::handle(
-                                    &mut handshake,
-                                    &json,
-                                    &table,
-                                    &to_client,
-                                    &to_server,
-                                    &name,
-                                    &id,
-                                    &mut send,
-                                ) {
-                                    Ok(_) => {}
-                                    Err(e) => {
-                                        println!("Failed to handle: {}", e);
-                                        break;
-                                    }
-                                }
+            // websocket clients have to finish the HTTP upgrade before any messages are exchanged,
+            // any other HTTP request on the websocket listener is for the dashboard
+            if transport == Transport::WebSocket {
+                let upgraded = match http::read_request(&mut stream) {
+                    Ok((request, leftover)) if websocket::is_upgrade(&request) => {
+                        match websocket::accept(&mut stream, &request) {
+                            Ok(_) => {
+                                session.frames.set_framing(Framing::WebSocket);
+                                let _ = session.frames.extend(&leftover);
+                                true
                             }
+                            Err(e) => {
+                                log_warn!("Failed websocket upgrade ({}): {}", id, e);
+                                false
+                            }
+                        }
+                    }
+                    Ok((request, _)) => {
+                        if let Err(e) = dashboard::serve(&mut stream, &request) {
+                            log_warn!("Failed to serve dashboard ({}): {}", id, e);
                         }
-                        None => {}
+                        false
+                    }
+                    Err(e) => {
+                        log_warn!("Failed to read HTTP request ({}): {}", id, e);
+                        false
                     }
+                };
+                
+                if !upgraded {
+                    let _ = stream.shutdown(Shutdown::Both);
+                    return;
+                }
+            }
+            
+            // from here on the thread sleeps until the client sends something or a message is queued for it
+            let mut stream = match stream.into_evented().and_then(|mut stream| {
+                poll.registry().register(stream.socket_mut(), SOCKET, Interest::READABLE | Interest::WRITABLE)?;
+                Ok(stream)
+            }) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log_warn!("Failed to set up connection ({}): {}", id, e);
+                    return;
+                }
+            };
+            log_info!("New connection with id: {}", id);
+            
+            let mut events = Events::with_capacity(8);
+            // encoded bytes the client hasn't taken yet
+            let mut output = Vec::new();
+            let mut terminating = false;
+            // when the client last took any output while some was still waiting
+            let mut stalled_since: Option<Instant> = None;
+            let mut last_ping = Instant::now();
+            let mut keepalive = false;
+            
+            // the first pass runs before waiting, anything the client sent along with the TLS handshake
+            // or websocket upgrade is already buffered and won't trigger an event
+            loop {
+                match Connection::read(&mut stream, &mut session, &table) {
+                    Ok(_) => {}
                     // connection aborted
-                    Err(ref e) if e.kind() == std::io::ErrorKind::ConnectionAborted => {
-                        stream.shutdown(Shutdown::Both).unwrap();
-                        println!("Connection aborted: ({}) {}", name.lock().unwrap().to_string(), id);
+                    Err(ref e) if e.kind() == ErrorKind::ConnectionAborted => {
+                        let _ = stream.shutdown(Shutdown::Both);
+                        log_info!("Connection aborted: ({}) {}", session.name.lock().unwrap(), id);
                         break;
                     }
                     Err(e) => {
                         // send last messages
-                        let mut to_client = to_client.lock().unwrap();
-                        let _ = Connection::write(&mut stream, &mut to_client);
+                        Connection::encode(&mut output, session.outgoing(), session.frames.framing());
+                        let _ = Connection::write(&mut stream, &mut output);
                         
-                        stream.shutdown(Shutdown::Both).unwrap();
+                        let _ = stream.shutdown(Shutdown::Both);
                         
-                        println!("Failed to read: {}", e.to_string());
+                        log_warn!("Failed to read: {}", e);
+                        break;
+                    }
+                }
+                
+                // a client that has gone quiet is pinged, and dropped if it still hasn't answered by the idle timeout
+                // only clients that can answer a ping are held to it: websocket clients, whose browsers answer ping frames,
+                // and clients that negotiated the heartbeat, but a client that never finishes its handshake is dropped too
+                let websocket = session.frames.framing() == Framing::WebSocket;
+                let heartbeats = session.features.contains(&"heartbeat");
+                // the rest are left to TCP keepalive once their handshake is done, timed so a dead one goes
+                // about when the idle timeout would have dropped it, while one that is only quiet is never disturbed
+                if session.handshake && !websocket && !heartbeats && !keepalive {
+                    keepalive = true;
+                    if let Some(interval) = heartbeat.or(idle_timeout) {
+                        let retries = idle_timeout.map_or(1, |timeout| (timeout.saturating_sub(interval).as_secs() / interval.as_secs().max(1)) as u32);
+                        if let Err(e) = stream.set_keepalive(interval, interval, retries.max(1)) {
+                            log_warn!("Failed to enable keepalive: ({}) {}: {}", session.name.lock().unwrap(), id, e);
+                        }
+                    }
+                }
+                let heartbeat = heartbeat.filter(|_| websocket || heartbeats);
+                let idle_timeout = idle_timeout.filter(|_| websocket || heartbeats || !session.handshake);
+                let idle = session.last_seen.lock().unwrap().elapsed();
+                if idle_timeout.is_some_and(|timeout| idle >= timeout) {
+                    log_info!("Connection timed out: ({}) {}", session.name.lock().unwrap(), id);
+                    send(&ProtocolError::new(ErrorCode::IdleTimeout, "Connection timed out").to_json("error", None), &session.to_client);
+                    Connection::encode(&mut output, session.outgoing(), session.frames.framing());
+                    let _ = Connection::write(&mut stream, &mut output);
+                    let _ = stream.shutdown(Shutdown::Both);
+                    break;
+                }
+                if heartbeat.is_some_and(|heartbeat| idle >= heartbeat && last_ping.elapsed() >= heartbeat) {
+                    // websocket clients answer a ping frame without any help from the application,
+                    // other clients are only pinged once they have negotiated it in the handshake
+                    if websocket {
+                        output.extend(websocket::encode_frame(websocket::OPCODE_PING, &[]));
+                    } else {
+                        send(&json!({"type": "ping"}), &session.to_client);
+                    }
+                    last_ping = Instant::now();
+                }
+                
+                // answer websocket pings before anything else
+                output.extend(session.frames.take_replies());
+                terminating |= Connection::encode(&mut output, session.outgoing(), session.frames.framing());
+                
+                let written = match Connection::write(&mut stream, &mut output) {
+                    Ok(written) => written,
+                    Err(e) => {
+                        log_warn!("Failed to write: ({}) {}: {}", session.name.lock().unwrap(), id, e);
+                        let _ = stream.shutdown(Shutdown::Both);
                         break;
                     }
+                };
+                
+                if output.is_empty() && !stream.wants_write() {
+                    stalled_since = None;
+                    if terminating {
+                        log_info!("Connection terminated: ({}) {}", session.name.lock().unwrap(), id);
+                        let _ = stream.shutdown(Shutdown::Both);
+                        break;
+                    }
+                } else if written > 0 || stalled_since.is_none() {
+                    stalled_since = Some(Instant::now());
                 }
                 
-                for connection in connections.lock().unwrap().values() {
-                    let mut messages = connection.to_server.lock().unwrap();
-                    Connection::write(&mut stream, &mut messages).unwrap();
+                // a client that stops reading is dropped rather than buffering for it forever
+                let stalled = stalled_since.is_some_and(|since| since.elapsed() >= WRITE_TIMEOUT);
+                if stalled || output.len() > MAX_OUTPUT {
+                    log_warn!("Client isn't keeping up, disconnecting: ({}) {}", session.name.lock().unwrap(), id);
+                    let _ = stream.shutdown(Shutdown::Both);
+                    break;
                 }
-                to_server.lock().unwrap().clear();
                 
-                match Connection::write(&mut stream, &mut to_client.lock().unwrap()) {
-                    Err(ref e) if e.kind() == ErrorKind::ConnectionAborted => {
-                        println!("Connection aborted: ({}) {}", name.lock().unwrap().to_string(), id);
+                // wake up on a timer for the next ping, the idle timeout, or while output is stuck behind a slow client
+                let idle = session.last_seen.lock().unwrap().elapsed();
+                let timeout = [
+                    heartbeat.map(|heartbeat| heartbeat.saturating_sub(idle.min(last_ping.elapsed()))),
+                    idle_timeout.map(|timeout| timeout.saturating_sub(idle)),
+                    stalled_since.map(|since| WRITE_TIMEOUT.saturating_sub(since.elapsed())),
+                ].into_iter().flatten().min();
+                if let Err(e) = poll.poll(&mut events, timeout) {
+                    if e.kind() != ErrorKind::Interrupted {
+                        log_warn!("Failed to poll: ({}) {}: {}", session.name.lock().unwrap(), id, e);
+                        let _ = stream.shutdown(Shutdown::Both);
                         break;
                     }
-                    _ => {}
                 }
             }
-            
-            let mut connections = connections.lock().unwrap();
-            connections.remove(&id);
         });
     }
     
+    #[allow(dead_code)]
     pub fn get_name(&self) -> String {
         let name = self.name.lock().unwrap();
         name.clone()
     }
     
     pub fn display(&self) {
+        let transport = match self.transport {
+            Transport::Tcp => "tcp",
+            Transport::WebSocket => "websocket",
+        };
+        let transport = match self.tls {
+            true => format!("{}+tls", transport),
+            false => transport.to_string(),
+        };
+        let last_seen = format!("last seen {}s ago", self.last_seen.lock().unwrap().elapsed().as_secs());
         if self.name.lock().unwrap().is_empty() {
-            println!("Connection ({})", self.id);
+            println!("Connection ({}, {}, {})", self.id, transport, last_seen);
         } else {
-            println!("{} (id: {})", self.name.lock().unwrap(), self.id);
+            println!("{} (id: {}, {}, {})", self.name.lock().unwrap(), self.id, transport, last_seen);
         }
     }
 
     pub fn send(&mut self, json_value: &Value) {
-        let mut buffer = self.to_client.lock().unwrap();
-        buffer.push(json_value.clone());
+        self.to_client.push(json_value.clone());
     }
     
     pub fn terminate(&mut self) {
-        let mut buffer = self.to_client.lock().unwrap();
-        buffer.push(json!({
+        self.to_client.push(json!({
             "terminate": true
         }));
     }
     
+    // handle one message from the client, sending back its response, an acknowledgement or an error
+    // a message may carry an id, which is echoed on whatever is sent back so the client can match them up
     fn handle(
-        handshake: &mut bool,
+        session: &mut Session,
         json: &Value,
         server_table: &Arc<Mutex<HashMap<String, Data>>>,
-        to_client: &Arc<Mutex<Vec<Value>>>,
-        to_server: &Arc<Mutex<Vec<Value>>>,
-        name: &Arc<Mutex<String>>,
-        id: &usize,
-        send: &mut dyn FnMut(&Value, &Arc<Mutex<Vec<Value>>>),
-    ) -> Result<(), Error> {
-        // println!("handle");
-        // check if the JSON object has a type
-        let response_type = match json.get("type") {
-            Some(response_type) => response_type.as_str().unwrap(),
-            None => "",
+    ) {
+        let id = json.get("id");
+        // anything that can't be told apart by its type is answered as an "error"
+        let (request_type, result) = match json.get("type") {
+            Some(Value::String(request_type)) => (request_type.as_str(), Connection::dispatch(session, request_type, json, server_table)),
+            Some(_) => ("error", Err(ProtocolError::invalid("type", "Expected type as a string"))),
+            None if !json.is_object() => ("error", Err(ProtocolError::new(ErrorCode::InvalidMessage, "Expected a JSON object"))),
+            None => ("error", Err(ProtocolError::invalid("type", "Missing message type"))),
         };
         
-        // if there's no response type, return an error
-        if response_type.is_empty() {
-            return Err(Error::new(ErrorKind::Other, "No response type"));
-        }
-        
-        match response_type {
-            "handshake" => {
-                if !*handshake {
-                    *handshake = true;
-                    
-                    let json_name = json.get("name").unwrap().as_str().unwrap();
-                    
-                    // if json_name includes any non-alphanumeric characters, return an error
-                    if !json_name.chars().all(|c| c.is_alphanumeric()) {
-                        // send a response
-                        let response = json!({
-                            "type": "handshake",
-                            "status": "error",
-                            "message": "Invalid client name",
-                            "terminate": true
-                        });
-                        
-                        send(&response, to_client);
-                    }
-                    
-                    let mut name = name.lock().unwrap();
-                    *name = json_name.to_string();
-                    
-                    // send the server table
-                    let table = Value::Object(server_table.lock().unwrap().iter().map(|(key, value)| {
-                        (key.clone(), value.to_json())
-                    }).collect());
-                    
-                    // send a response
-                    let response = json!({
-                        "type": "handshake",
-                        "status": "ok",
-                        "id": id,
-                        "table": table
-                    });
-                    
-                    send(&response, to_client);
-                    
-                    return Ok(());
+        match result {
+            Ok(Reply::Response(mut response)) => {
+                if let Some(id) = id {
+                    response["id"] = id.clone();
                 }
-                
-                Err(Error::new(ErrorKind::Other, "Handshake already completed"))
+                send(&response, &session.to_client);
             }
-            "update" => {
-                // get the table from the JSON object
-                match json.get("table") {
-                    Some(table) => {
-                        // iterate over the table
-                        let table = table.as_object().unwrap();
-                        for (key, value) in table.iter() {
-                            // update the server table
-                            server_table.lock().unwrap().insert(key.clone(), Data::from_json(value));
+            Ok(Reply::Ack(details)) => {
+                // messages that change something are only acknowledged if the client asked, by sending an id,
+                // and negotiated acknowledgements
+                if let Some(id) = id.filter(|_| session.features.contains(&"ack")) {
+                    let mut ack = json!({
+                        "type": "ack",
+                        "status": "ok",
+                        "request": request_type,
+                        "id": id
+                    });
+                    if let Value::Object(details) = details {
+                        for (field, value) in details {
+                            ack[field] = value;
                         }
-                        
-                        send(&json!({
-                            "type": "update",
-                            "status": "ok",
-                            "table": Value::Object(table.clone())
-                        }), to_server);
-                        
-                        Ok(())
                     }
-                    None => Err(Error::new(ErrorKind::Other, "No table in JSON object"))
+                    send(&ack, &session.to_client);
+                }
+            }
+            Ok(Reply::Sent) => {}
+            Err(error) => {
+                log_debug!("Rejected {} from ({}) {}: {}", request_type, session.name.lock().unwrap(), session.id, error.message);
+                send(&error.to_json(request_type, id), &session.to_client);
+            }
+        }
+    }
+    
+    fn dispatch(
+        session: &mut Session,
+        request_type: &str,
+        json: &Value,
+        server_table: &Arc<Mutex<HashMap<String, Data>>>,
+    ) -> Result<Reply, ProtocolError> {
+        if !session.handshake && !protocol::is_anonymous(request_type) {
+            return Err(ProtocolError::new(ErrorCode::HandshakeRequired, "Handshake required"));
+        }
+        // a client that left a feature out is treated as if its messages didn't exist
+        if let Some(feature) = protocol::feature(request_type).filter(|feature| !session.features.contains(feature)) {
+            return Err(ProtocolError::new(ErrorCode::UnknownType, format!("Unknown message type: {} (the {} feature wasn't negotiated)", request_type, feature)));
+        }
+        
+        match request_type {
+            "protocol" => {
+                let mut response = protocol::describe();
+                response["type"] = json!("protocol");
+                response["status"] = json!("ok");
+                Ok(Reply::Response(response))
+            }
+            "handshake" => Connection::handshake(session, json, server_table),
+            "update" => Connection::update(session, json, server_table),
+            "delete" => Connection::delete(session, json, server_table),
+            "patch" => Connection::patch(session, json, server_table),
+            "schema" => Connection::declare(session, json, server_table),
+            "ping" => Ok(Reply::Response(json!({"type": "pong", "status": "ok"}))),
+            // the answer to a heartbeat, receiving it is all that matters
+            "pong" => Ok(Reply::Sent),
+            "get" => Connection::get(session, json, server_table),
+            "subscribe" | "unsubscribe" => {
+                let patterns = match json.get("keys") {
+                    // unsubscribing without keys drops every subscription
+                    None if request_type == "unsubscribe" => Vec::new(),
+                    _ => string_array(json, "keys")?,
+                };
+                Ok(Connection::subscribe(session, request_type, patterns, server_table))
+            }
+            _ => Err(ProtocolError::new(ErrorCode::UnknownType, format!("Unknown message type: {}", request_type))),
+        }
+    }
+    
+    fn handshake(
+        session: &mut Session,
+        json: &Value,
+        server_table: &Arc<Mutex<HashMap<String, Data>>>,
+    ) -> Result<Reply, ProtocolError> {
+        if session.handshake {
+            return Err(ProtocolError::new(ErrorCode::HandshakeCompleted, "Handshake already completed"));
+        }
+        
+        // a failed handshake ends the connection
+        let reject = |code: ErrorCode, message: String| ProtocolError::new(code, message).with("terminate", true);
+        
+        // a malformed handshake can be corrected and sent again, only a rejected one ends the connection
+        let json_name = json.get("name").and_then(|name| name.as_str())
+            .ok_or_else(|| ProtocolError::invalid("name", "Expected name as a string"))?;
+        
+        // if json_name includes any non-alphanumeric characters, return an error
+        if !json_name.chars().all(|c| c.is_alphanumeric()) {
+            return Err(reject(ErrorCode::InvalidName, "Invalid client name".to_string()));
+        }
+        
+        // settled before authenticating, so a client on the wrong version doesn't use up its attempts
+        let (version, features) = protocol::negotiate(json).map_err(|e| match e.code {
+            ErrorCode::UnsupportedVersion => e.with("terminate", true),
+            _ => e,
+        })?;
+        
+        let password = json.get("password").and_then(|password| password.as_str());
+        let token = json.get("token").and_then(|token| token.as_str());
+        // a verified client certificate stands in for the password or token,
+        // but only for the client named in the certificate
+        let access = match &session.identity {
+            Some(identity) if identity == json_name => Ok(session.auth.access(json_name)),
+            Some(identity) => {
+                log_warn!("Rejected handshake from {} ({}) {}: certificate is for {}", session.address, json_name, session.id, identity);
+                return Err(reject(ErrorCode::CertificateMismatch, "Client name does not match certificate".to_string()));
+            }
+            None => session.auth.check(session.address, json_name, password, token),
+        };
+        session.access = match access {
+            Ok(access) => access,
+            Err(e) => {
+                log_warn!("Rejected handshake from {} ({}) {}: {}", session.address, json_name, session.id, e.message());
+                let code = match e {
+                    AuthError::Failed => ErrorCode::AuthenticationFailed,
+                    AuthError::LockedOut(_) => ErrorCode::LockedOut,
+                };
+                return Err(reject(code, e.message()));
+            }
+        };
+        
+        // the client may ask for a different framing, which applies from the handshake response onwards
+        // websocket clients are already framed by the transport
+        if let Some(framing) = json.get("framing").filter(|_| session.frames.framing() != Framing::WebSocket) {
+            match framing.as_str().and_then(Framing::from_name) {
+                Some(framing) => session.frames.set_framing(framing),
+                None => return Err(reject(ErrorCode::UnsupportedFraming, "Unsupported framing".to_string()).with("field", "framing")),
+            }
+        }
+        
+        let mut name = session.name.lock().unwrap();
+        *name = json_name.to_string();
+        session.handshake = true;
+        session.features = features.clone();
+        
+        // send the part of the server table the client may read
+        // the table stays locked until the client is subscribed, so no update can slip in between
+        let server_table = server_table.lock().unwrap();
+        let table: serde_json::Map<String, Value> = server_table.iter()
+            .filter(|(key, _)| session.access.can_read(key))
+            .map(|(key, value)| (key.clone(), value.to_json()))
+            .collect();
+        
+        // send a response
+        let response = json!({
+            "type": "handshake",
+            "status": "ok",
+            "client_id": session.id,
+            // the request id is echoed like on any response, clients that didn't send one get their connection id as before
+            "id": json.get("id").cloned().unwrap_or_else(|| json!(session.id)),
+            "framing": session.frames.framing().name(),
+            "version": version,
+            "features": features,
+            "permissions": session.access.to_json(),
+            "schema": session.schemas.to_json(|key| session.access.can_read(key)),
+            "versions": session.versions.to_json(&server_table, table.keys()),
+            "table": table
+        });
+        
+        // queued here rather than returned, so it is ahead of any update published once the client is subscribed
+        send(&response, &session.to_client);
+        session.bus.subscribe(session.id, session.to_client.clone(), session.access.clone());
+        
+        Ok(Reply::Sent)
+    }
+    
+    fn update(
+        session: &mut Session,
+        json: &Value,
+        server_table: &Arc<Mutex<HashMap<String, Data>>>,
+    ) -> Result<Reply, ProtocolError> {
+        // get the table from the JSON object
+        let table = json.get("table").and_then(|table| table.as_object())
+            .ok_or_else(|| ProtocolError::invalid("table", "Expected table as an object"))?;
+        
+        // reject the whole update if any key is off limits, so it is never half applied
+        if let Some(key) = table.keys().find(|key| !session.access.can_write(key)) {
+            return Err(ProtocolError::permission_denied(key));
+        }
+        
+        let mut server_table = server_table.lock().unwrap();
+        
+        // keys are paths into the table, reject the update if any of them can't be followed,
+        // including through a value set by an earlier key in the same update, or if a value doesn't match its schema
+        let values: Vec<(&str, Data)> = table.iter().map(|(key, value)| (key.as_str(), Data::from_json(value))).collect();
+        if let Err((key, message)) = path::check_set_all(&server_table, &values) {
+            return Err(ProtocolError::new(ErrorCode::InvalidPath, message).with("key", key));
+        }
+        for (key, value) in table.iter() {
+            if let Err(message) = session.schemas.check(key, value) {
+                return Err(ProtocolError::new(ErrorCode::InvalidValue, message).with("key", key.clone()));
+            }
+        }
+        Connection::expect(session, json, &server_table)?;
+        
+        let name = session.name.lock().unwrap().clone();
+        let mut applied = serde_json::Map::new();
+        for ((key, value), (_, data)) in table.iter().zip(values) {
+            // update the server table
+            if let Err(e) = path::set(&mut server_table, key, data) {
+                // every key was checked in order above, so this can't happen
+                log_warn!("Skipped {} in update from ({}) {}: {}", key, name, session.id, e);
+                continue;
+            }
+            session.versions.touch(&server_table, key, Some(session.id));
+            // journal while still holding the table lock so the log order matches the table
+            if let Some(journal) = &session.journal {
+                journal.record(Some(session.id), &name, key, value);
+            }
+            applied.insert(key.clone(), value.clone());
+        }
+        
+        // publish before releasing the table so other clients see updates in the order they were applied
+        // nested keys go out as the path and the value at it, not the whole object
+        let versions = session.versions.to_json(&server_table, applied.keys());
+        session.bus.publish(Some(session.id), &json!({
+            "type": "update",
+            "status": "ok",
+            "versions": versions,
+            "table": Value::Object(applied)
+        }));
+        drop(server_table);
+        
+        Ok(Reply::Ack(json!({ "versions": versions })))
+    }
+    
+    fn delete(
+        session: &mut Session,
+        json: &Value,
+        server_table: &Arc<Mutex<HashMap<String, Data>>>,
+    ) -> Result<Reply, ProtocolError> {
+        let keys = string_array(json, "keys")?;
+        
+        // deleting needs write access, and like updates the whole request is rejected if any key is off limits
+        if let Some(key) = keys.iter().find(|key| !session.access.can_write(key)) {
+            return Err(ProtocolError::permission_denied(key));
+        }
+        
+        let mut server_table = server_table.lock().unwrap();
+        Connection::expect(session, json, &server_table)?;
+        let name = session.name.lock().unwrap().clone();
+        // keys that don't exist are skipped, only real removals are journaled and broadcast
+        let deleted: Vec<String> = keys.into_iter()
+            .filter(|key| path::remove(&mut server_table, key).is_some())
+            .collect();
+        for key in &deleted {
+            session.versions.touch(&server_table, key, Some(session.id));
+        }
+        if let Some(journal) = &session.journal {
+            for key in &deleted {
+                journal.record_delete(Some(session.id), &name, key);
+            }
+        }
+        
+        if !deleted.is_empty() {
+            session.bus.publish(Some(session.id), &json!({
+                "type": "delete",
+                "status": "ok",
+                "keys": deleted
+            }));
+        }
+        drop(server_table);
+        
+        Ok(Reply::Ack(json!({ "keys": deleted })))
+    }
+    
+    fn patch(
+        session: &mut Session,
+        json: &Value,
+        server_table: &Arc<Mutex<HashMap<String, Data>>>,
+    ) -> Result<Reply, ProtocolError> {
+        let key = json.get("key").and_then(|key| key.as_str())
+            .ok_or_else(|| ProtocolError::invalid("key", "Expected key as a string"))?;
+        let operations = json.get("patch").and_then(|patch| patch.as_array())
+            .ok_or_else(|| ProtocolError::invalid("patch", "Expected patch as an array of operations"))?;
+        
+        // patching needs read access too, whether an operation fails gives away what the value holds,
+        // most directly with test
+        if !session.access.can_write(key) || !session.access.can_read(key) {
+            return Err(ProtocolError::permission_denied(key));
+        }
+        
+        let mut server_table = server_table.lock().unwrap();
+        Connection::expect(session, json, &server_table)?;
+        
+        // the patch is applied to a copy, so a failing operation leaves the stored value untouched
+        let data = path::get(&server_table, key)
+            .ok_or_else(|| ProtocolError::new(ErrorCode::NotFound, format!("No such key: {}", key)).with("key", key))?;
+        let patched = patch::apply(data, operations)
+            .map_err(|message| ProtocolError::new(ErrorCode::PatchFailed, message).with("key", key))?;
+        session.schemas.check(key, &patched.to_json())
+            .map_err(|message| ProtocolError::new(ErrorCode::InvalidValue, message).with("key", key))?;
+        
+        // the journal stores the patched value, so replaying it doesn't depend on the patch
+        if let Some(journal) = &session.journal {
+            let name = session.name.lock().unwrap().clone();
+            journal.record(Some(session.id), &name, key, &patched.to_json());
+        }
+        if let Err(e) = path::set(&mut server_table, key, patched) {
+            // the key was just read, so its path can always be set
+            log_warn!("Failed to store patch of {}: {}", key, e);
+        }
+        session.versions.touch(&server_table, key, Some(session.id));
+        
+        // other clients get the same patch rather than the whole value,
+        // which only goes to clients subscribed to paths inside the key, as an update of those paths
+        let version = session.versions.get(&server_table, key);
+        session.bus.publish(Some(session.id), &json!({
+            "type": "patch",
+            "status": "ok",
+            "key": key,
+            "version": version,
+            "patch": operations,
+            "value": path::get(&server_table, key).map(|value| value.to_json()),
+            "versions": session.versions.to_json(&server_table, std::iter::once(&key.to_string()))
+        }));
+        drop(server_table);
+        
+        Ok(Reply::Ack(json!({ "version": version })))
+    }
+    
+    // answer a request for the current values of some keys, by name or by prefix
+    fn get(
+        session: &mut Session,
+        json: &Value,
+        server_table: &Arc<Mutex<HashMap<String, Data>>>,
+    ) -> Result<Reply, ProtocolError> {
+        let keys = json.get("keys").map(|_| string_array(json, "keys")).transpose()?;
+        let prefix = match json.get("prefix") {
+            Some(Value::String(prefix)) => Some(prefix.as_str()),
+            Some(_) => return Err(ProtocolError::invalid("prefix", "Expected prefix as a string")),
+            None => None,
+        };
+        if keys.is_none() && prefix.is_none() {
+            return Err(ProtocolError::new(ErrorCode::InvalidMessage, "Expected keys or prefix"));
+        }
+        
+        // asking for a key by name that the client can't read is an error, like writing one
+        if let Some(key) = keys.iter().flatten().find(|key| !session.access.can_read(key)) {
+            return Err(ProtocolError::permission_denied(key));
+        }
+        
+        let server_table = server_table.lock().unwrap();
+        let mut table = serde_json::Map::new();
+        let mut missing = Vec::new();
+        for key in keys.iter().flatten() {
+            match path::get(&server_table, key) {
+                Some(value) => {
+                    table.insert(key.clone(), value.to_json());
+                }
+                None => missing.push(key.clone()),
+            }
+        }
+        if let Some(prefix) = prefix {
+            for (key, value) in server_table.iter() {
+                if key.starts_with(prefix) && session.access.can_read(key) {
+                    table.insert(key.clone(), value.to_json());
                 }
             }
-            _ => {
-                Err(Error::new(ErrorKind::Other, "Invalid response type"))
+        }
+        let versions = session.versions.to_json(&server_table, table.keys());
+        drop(server_table);
+        
+        Ok(Reply::Response(json!({
+            "type": "get",
+            "status": "ok",
+            "table": table,
+            "versions": versions,
+            "missing": missing
+        })))
+    }
+    
+    // check the versions a conditional request expects, e.g. {"type": "update", "table": {...}, "expect": {"speed": 12}}
+    // 0 expects the key not to exist
+    fn expect(session: &Session, json: &Value, server_table: &HashMap<String, Data>) -> Result<(), ProtocolError> {
+        let expected = match json.get("expect") {
+            Some(Value::Object(expected)) => expected,
+            Some(_) => return Err(ProtocolError::invalid("expect", "Expected expect as an object of versions")),
+            None => return Ok(()),
+        };
+        
+        for (key, version) in expected {
+            let version = version.as_u64()
+                .ok_or_else(|| ProtocolError::invalid("expect", format!("Invalid version for key: {}", key)).with("key", key.clone()))?;
+            // a client can't learn anything about keys it can't read
+            if !session.access.can_read(key) {
+                return Err(ProtocolError::permission_denied(key));
+            }
+            let current = session.versions.get(server_table, key);
+            if current != version {
+                return Err(ProtocolError::new(ErrorCode::VersionConflict, format!("Version conflict for key: {}", key))
+                    .with("key", key.clone())
+                    .with("version", current)
+                    .with("expected", version));
+            }
+        }
+        Ok(())
+    }
+    
+    // declare schemas for keys, e.g. {"type": "schema", "schema": {"speed": {"type": "number", "min": 0}, "old": null}}
+    // null removes a schema, and like updates nothing changes unless every schema is accepted
+    fn declare(
+        session: &mut Session,
+        json: &Value,
+        server_table: &Arc<Mutex<HashMap<String, Data>>>,
+    ) -> Result<Reply, ProtocolError> {
+        let declared = json.get("schema").and_then(|schema| schema.as_object())
+            .ok_or_else(|| ProtocolError::invalid("schema", "Expected schema as an object"))?;
+        
+        // hold the table so current values can't change between being checked and the schema taking effect
+        let server_table = server_table.lock().unwrap();
+        
+        let mut schemas = Vec::new();
+        for (key, schema) in declared {
+            let error = |code: ErrorCode, message: String| ProtocolError::new(code, message).with("key", key.clone());
+            // a schema rejected for the current value would give the value away, as a patch test would
+            if !session.access.can_write(key) || !session.access.can_read(key) {
+                return Err(ProtocolError::permission_denied(key));
+            }
+            if let Err(message) = session.schemas.can_declare(key) {
+                return Err(error(ErrorCode::SchemaLocked, message));
+            }
+            let schema = match schema {
+                Value::Null => None,
+                schema => match Schema::from_json(schema) {
+                    Ok(schema) => Some(schema),
+                    Err(message) => return Err(error(ErrorCode::InvalidSchema, format!("Invalid schema for {}: {}", key, message))),
+                },
+            };
+            // a key that already has a value must match its new schema
+            if let (Some(schema), Some(current)) = (&schema, path::get(&server_table, key)) {
+                if let Err(message) = schema.check(&current.to_json()) {
+                    return Err(error(ErrorCode::InvalidValue, format!("Current value of {} doesn't match the schema: {}", key, message)));
+                }
             }
+            schemas.push((key, schema));
+        }
+        
+        let mut published = serde_json::Map::new();
+        for (key, schema) in schemas {
+            published.insert(key.clone(), schema.as_ref().map_or(Value::Null, |schema| schema.to_json()));
+            session.schemas.declare(key, schema);
+        }
+        
+        session.bus.publish(Some(session.id), &json!({
+            "type": "schema",
+            "status": "ok",
+            "schema": Value::Object(published)
+        }));
+        drop(server_table);
+        
+        Ok(Reply::Ack(json!({})))
+    }
+    
+    // change which keys the client receives updates for
+    // new subscriptions come back with the current values of the keys they match
+    fn subscribe(
+        session: &mut Session,
+        response_type: &str,
+        patterns: Vec<String>,
+        server_table: &Arc<Mutex<HashMap<String, Data>>>,
+    ) -> Reply {
+        // hold the table so no update is published between the values sent here and the new filter taking effect
+        let server_table = server_table.lock().unwrap();
+        
+        let mut table = serde_json::Map::new();
+        if response_type == "subscribe" {
+            // the first subscription replaces the default of receiving everything
+            let subscriptions = session.subscriptions.get_or_insert_with(Vec::new);
+            for pattern in &patterns {
+                if !subscriptions.contains(pattern) {
+                    subscriptions.push(pattern.clone());
+                }
+            }
+            // like updates, a pattern reaching into a key with wildcards gets the whole value
+            let inside = |pattern: &String, key: &str| pattern.contains(['*', '?'])
+                && pattern.strip_prefix(key).is_some_and(|rest| rest.starts_with(path::SEPARATOR));
+            for (key, value) in server_table.iter() {
+                if session.access.can_read(key) && patterns.iter().any(|pattern| glob_match(pattern, key) || inside(pattern, key)) {
+                    table.insert(key.clone(), value.to_json());
+                }
+            }
+            // a pattern naming a nested path gets the value at that path
+            for pattern in &patterns {
+                if table.contains_key(pattern) || pattern.contains(['*', '?']) || !session.access.can_read(pattern) {
+                    continue;
+                }
+                if let Some(value) = path::get(&server_table, pattern) {
+                    table.insert(pattern.clone(), value.to_json());
+                }
+            }
+        } else if patterns.is_empty() {
+            session.subscriptions = Some(Vec::new());
+        } else if let Some(subscriptions) = &mut session.subscriptions {
+            // patterns are removed as given, unsubscribing "speed" doesn't undo a subscription to "*"
+            subscriptions.retain(|pattern| !patterns.contains(pattern));
+        }
+        
+        session.bus.set_keys(session.id, session.subscriptions.clone());
+        
+        let mut response = json!({
+            "type": response_type,
+            "status": "ok",
+            "keys": session.subscriptions.clone().unwrap_or_else(|| vec!["*".to_string()])
+        });
+        if response_type == "subscribe" {
+            response["table"] = Value::Object(table);
         }
+        Reply::Response(response)
     }
 
+    // read until the socket has nothing more to give, then handle every complete message
+    // sockets are edge triggered, so anything left unread wouldn't wake the thread again
     fn read(
-        stream: &mut TcpStream,
-    ) -> Result<Option<Vec<Value>>, Error>{
-        let mut buffer = [0; 2048];
-        match stream.read(&mut buffer) {
-            Ok(bytes_read) => {
-                let json_string = String::from_utf8_lossy(&buffer[..bytes_read]);
-                // sometimes the client sends too many messages at once and they're read like:
-                // { ... }{ ... }{ ... }
-                // split this into separate messages and return a vector of JSON objects
-                let json_strings: Vec<&str> = json_string.split("}{").collect();
-                
-                let mut json_values = Vec::new();
-                
-                for string in json_strings {
-                    let mut json_string = string.to_string();
-                    // if the string is empty, skip it
-                    if json_string.is_empty() {
-                        continue;
-                    }
-                    
-                    // if the string doesn't start with a {, add one
-                    if !json_string.starts_with("{") {
-                        json_string = format!("{}{}", "{".to_string(), json_string);
-                    }
-                    // if the string doesn't end with a }, add one
-                    if !json_string.ends_with("}") {
-                        json_string = format!("{}{}", json_string, "}".to_string());
+        stream: &mut EventedStream,
+        session: &mut Session,
+        table: &Arc<Mutex<HashMap<String, Data>>>,
+    ) -> Result<(), Error> {
+        let mut buffer = [0; 4096];
+        loop {
+            match stream.read(&mut buffer) {
+                // the client closed its end of the socket, anything it sent before that is still handled
+                Ok(0) => {
+                    Connection::process_buffered(stream, session, table)?;
+                    return Err(Error::new(ErrorKind::ConnectionAborted, "Connection closed by client"));
+                }
+                // messages may arrive split across reads or several at once,
+                // the frame buffer holds on to partial messages until the rest arrives
+                Ok(bytes_read) => {
+                    *session.last_seen.lock().unwrap() = Instant::now();
+                    if let Err(e) = session.frames.extend(&buffer[..bytes_read]) {
+                        send(&ProtocolError::new(ErrorCode::InvalidFrame, e.to_string()).to_json("error", None), &session.to_client);
+                        return Err(e);
                     }
-                    
-                    match serde_json::from_str(&json_string) {
-                        Ok(json) => {
-                            json_values.push(json);
-                        }
-                        Err(e) => {
-                            println!("Failed to parse JSON: {}", e);
-                            println!("JSON: {}", json_string);
-                        }
+                    // a client sending faster than it is read could otherwise fill the buffer with complete messages
+                    if session.frames.buffered() > PROCESS_THRESHOLD {
+                        Connection::process_buffered(stream, session, table)?;
                     }
                 }
-                
-                Ok(Some(json_values))
-            }
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                Ok(None)
-            }
-            Err(_) => {
-                Err(Error::new(ErrorKind::ConnectionAborted, "Failed to read from stream"))
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Connection::process_buffered(stream, session, table),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    return Err(Error::new(ErrorKind::ConnectionAborted, "Failed to read from stream"));
+                }
             }
         }
     }
-
-    fn write(
-        stream: &mut TcpStream,
-        message_buffer: &mut Vec<Value>,
+    
+    fn process_buffered(
+        stream: &mut EventedStream,
+        session: &mut Session,
+        table: &Arc<Mutex<HashMap<String, Data>>>,
     ) -> Result<(), Error> {
-        while !message_buffer.is_empty() {
-            let json_value = message_buffer.remove(0);
-            let json_string = &json_value.to_string();
-            
-            let bytes = json_string.as_bytes();
-            
-            match stream.write_all(bytes) {
-                Ok(_) => {}
+        Connection::process(session, table).inspect_err(|_| {
+            // the websocket close has to go out before the connection does
+            let mut replies = session.frames.take_replies();
+            let _ = Connection::write(stream, &mut replies);
+        })
+    }
+    
+    // handle every complete message in the frame buffer
+    // a message that isn't valid JSON is answered with an error and skipped, but a frame that can't be read leaves
+    // the buffer out of step with the stream, so that ends the connection once the error has been sent
+    fn process(
+        session: &mut Session,
+        table: &Arc<Mutex<HashMap<String, Data>>>,
+    ) -> Result<(), Error> {
+        loop {
+            let json = match session.frames.next_message() {
+                Ok(Some(json)) => json,
+                Ok(None) => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::InvalidData => {
+                    log_debug!("Failed to parse JSON: ({}) {}: {}", session.name.lock().unwrap(), session.id, e);
+                    send(&ProtocolError::new(ErrorCode::InvalidJson, format!("Invalid JSON: {}", e)).to_json("error", None), &session.to_client);
+                    continue;
+                }
+                Err(e) if e.kind() == ErrorKind::ConnectionAborted => return Err(e),
                 Err(e) => {
+                    log_warn!("Failed to read frame: ({}) {}: {}", session.name.lock().unwrap(), session.id, e);
+                    send(&ProtocolError::new(ErrorCode::InvalidFrame, e.to_string()).to_json("error", None), &session.to_client);
                     return Err(e);
                 }
-            }
+            };
+            
+            Connection::handle(session, &json, table);
+        }
+    }
+    
+    // encode queued messages onto the output, returning true if one of them ends the connection
+    fn encode(output: &mut Vec<u8>, messages: Vec<Value>, framing: Framing) -> bool {
+        for json_value in messages {
+            output.extend(framing.encode(&json_value));
             
-            // if there is a terminate: true, terminate the stream
+            // if there is a terminate: true, nothing after it is sent
             if json_value.get("terminate") == Some(&Value::Bool(true)) {
-                println!("Terminating stream");
-                stream.shutdown(Shutdown::Both)?;
-                return Err(Error::new(ErrorKind::ConnectionAborted, "Terminating stream"));
+                log_debug!("Terminating stream");
+                if framing == Framing::WebSocket {
+                    output.extend(websocket::encode_frame(websocket::OPCODE_CLOSE, &[]));
+                }
+                return true;
+            }
+        }
+        false
+    }
+    
+    // write as much of the output as the socket will take without blocking, returning how many bytes it took
+    fn write(
+        stream: &mut EventedStream,
+        output: &mut Vec<u8>,
+    ) -> Result<usize, Error> {
+        let mut written = 0;
+        while !output.is_empty() {
+            match stream.write(output) {
+                Ok(0) => return Err(Error::new(ErrorKind::WriteZero, "Failed to write to stream")),
+                Ok(bytes_written) => {
+                    output.drain(..bytes_written);
+                    written += bytes_written;
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(written),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
             }
         }
         
-        Ok(())
+        // push out anything TLS is still holding on to
+        match stream.flush() {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(written),
+            result => result.map(|_| written),
+        }
+    }
+}
+// state owned by a single connection thread
+struct Session {
+    id: usize,
+    address: IpAddr,
+    handshake: bool,
+    frames: FrameBuffer,
+    name: Arc<Mutex<String>>,
+    last_seen: Arc<Mutex<Instant>>,
+    to_client: Arc<Outbox>,
+    journal: Option<Arc<Journal>>,
+    auth: Arc<Auth>,
+    bus: Arc<Bus>,
+    schemas: Arc<Schemas>,
+    versions: Arc<Versions>,
+    // what the client may read and write, set once the handshake is accepted
+    access: Access,
+    // the common name of a verified client certificate
+    identity: Option<String>,
+    // glob patterns of the keys the client wants updates for, None until it first subscribes or unsubscribes
+    subscriptions: Option<Vec<String>>,
+    // the optional parts of the protocol the client negotiated, those of the first version until the handshake
+    features: Vec<&'static str>,
+}
+
+impl Session {
+    // everything queued for the client, in the form it negotiated
+    fn outgoing(&self) -> Vec<Value> {
+        self.to_client.take().into_iter().filter_map(|message| protocol::downgrade(&self.features, message)).collect()
     }
-}
\ No newline at end of file
+}
+
+// removes a connection from the server once its thread ends, however it ends,
+// so a panic while handling a message can't leave it listed with nobody serving it
+struct Registration {
+    id: usize,
+    connections: Arc<Mutex<HashMap<usize, Connection>>>,
+    bus: Arc<Bus>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.bus.unsubscribe(self.id);
+        // a thread that panicked while holding the lock has poisoned it, the map itself is still fine
+        self.connections.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.id);
+    }
+}
+
+// what handling a message sends back to the client, besides anything it publishes to the others
+enum Reply {
+    // a response the client always gets, like the values asked for by get
+    Response(Value),
+    // the message changed something, acknowledged with these details if the client sent an id
+    Ack(Value),
+    // nothing more to send, e.g. the response has already been queued
+    Sent,
+}
+
+fn send(json_value: &Value, outbox: &Outbox) {
+    outbox.push(json_value.clone());
+}
+
+// a required field holding an array of strings, e.g. the keys of a delete
+fn string_array(json: &Value, field: &str) -> Result<Vec<String>, ProtocolError> {
+    let strings: Option<Vec<String>> = match json.get(field) {
+        Some(Value::Array(values)) => values.iter().map(|value| value.as_str().map(|value| value.to_string())).collect(),
+        _ => None,
+    };
+    strings.ok_or_else(|| ProtocolError::invalid(field, format!("Expected {} as an array of strings", field)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use crate::config::Config;
+    use super::*;
+
+    // a session as the connection thread would have it, without a socket behind it
+    fn session() -> (Session, Poll) {
+        let poll = Poll::new().unwrap();
+        let waker = Waker::new(poll.registry(), WAKER).unwrap();
+        let mut frames = FrameBuffer::new();
+        frames.set_framing(Framing::Ndjson);
+        let session = Session {
+            id: 1,
+            address: IpAddr::from([127, 0, 0, 1]),
+            handshake: false,
+            frames,
+            name: Arc::new(Mutex::new(String::new())),
+            last_seen: Arc::new(Mutex::new(Instant::now())),
+            to_client: Arc::new(Outbox { messages: Mutex::new(Vec::new()), waker }),
+            journal: None,
+            auth: Arc::new(Auth::from_config(&Config::default())),
+            bus: Arc::new(Bus::default()),
+            schemas: Arc::new(Schemas::new(&BTreeMap::new())),
+            versions: Arc::new(Versions::default()),
+            access: Access::default(),
+            identity: None,
+            subscriptions: None,
+            features: protocol::features(1),
+        };
+        (session, poll)
+    }
+
+    // feed bytes through the frame buffer and handler, returning whether the connection survives and what was sent back
+    fn feed(session: &mut Session, bytes: &[u8]) -> (bool, Vec<Value>) {
+        let table = Arc::new(Mutex::new(HashMap::new()));
+        let alive = session.frames.extend(bytes).is_ok() && Connection::process(session, &table).is_ok();
+        (alive, session.outgoing())
+    }
+
+    fn error<'a>(responses: &'a [Value], code: &str) -> &'a Value {
+        assert_eq!(responses.len(), 1, "expected one response: {:?}", responses);
+        assert_eq!(responses[0]["status"], "error");
+        assert_eq!(responses[0]["code"], code);
+        &responses[0]
+    }
+
+    #[test]
+    fn non_string_type() {
+        let (mut session, _poll) = session();
+        let (alive, responses) = feed(&mut session, b"{\"type\": 5, \"id\": 7}\n");
+        assert!(alive);
+        let response = error(&responses, "invalid_message");
+        assert_eq!(response["type"], "error");
+        assert_eq!(response["field"], "type");
+        assert_eq!(response["id"], 7);
+    }
+
+    #[test]
+    fn missing_type() {
+        let (mut session, _poll) = session();
+        let (alive, responses) = feed(&mut session, b"{\"name\": \"client\"}\n");
+        assert!(alive);
+        assert_eq!(error(&responses, "invalid_message")["field"], "type");
+    }
+
+    #[test]
+    fn non_object_message() {
+        let (mut session, _poll) = session();
+        let (alive, responses) = feed(&mut session, b"[1, 2, 3]\n\"handshake\"\n");
+        assert!(alive);
+        assert_eq!(responses.len(), 2);
+        error(&responses[..1], "invalid_message");
+        error(&responses[1..], "invalid_message");
+    }
+
+    #[test]
+    fn missing_name_can_be_retried() {
+        let (mut session, _poll) = session();
+        let (alive, responses) = feed(&mut session, b"{\"type\": \"handshake\"}\n");
+        assert!(alive);
+        let response = error(&responses, "invalid_message");
+        assert_eq!(response["type"], "handshake");
+        assert_eq!(response["field"], "name");
+        assert!(response.get("terminate").is_none());
+
+        let (alive, responses) = feed(&mut session, b"{\"type\": \"handshake\", \"name\": 5}\n");
+        assert!(alive);
+        assert_eq!(error(&responses, "invalid_message")["field"], "name");
+
+        let (alive, responses) = feed(&mut session, b"{\"type\": \"handshake\", \"name\": \"client\"}\n");
+        assert!(alive);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0]["status"], "ok");
+        assert!(session.handshake);
+    }
+
+    #[test]
+    fn non_object_table() {
+        let (mut session, _poll) = session();
+        feed(&mut session, b"{\"type\": \"handshake\", \"name\": \"client\"}\n");
+        for table in ["[1]", "\"a\"", "null", "3"] {
+            let message = format!("{{\"type\": \"update\", \"table\": {}}}\n", table);
+            let (alive, responses) = feed(&mut session, message.as_bytes());
+            assert!(alive);
+            let response = error(&responses, "invalid_message");
+            assert_eq!(response["type"], "update");
+            assert_eq!(response["field"], "table");
+        }
+        let (alive, responses) = feed(&mut session, b"{\"type\": \"update\"}\n");
+        assert!(alive);
+        assert_eq!(error(&responses, "invalid_message")["field"], "table");
+    }
+
+    #[test]
+    fn update_through_earlier_key() {
+        let (mut session, _poll) = session();
+        let table = Arc::new(Mutex::new(HashMap::new()));
+        let mut feed = |bytes: &[u8]| {
+            session.frames.extend(bytes).unwrap();
+            Connection::process(&mut session, &table).unwrap();
+            session.to_client.take()
+        };
+        feed(b"{\"type\": \"handshake\", \"name\": \"client\"}\n");
+        feed(b"{\"type\": \"update\", \"table\": {\"drive\": {\"pid\": {}}}}\n");
+        let responses = feed(b"{\"type\": \"update\", \"id\": 1, \"table\": {\"drive/pid\": 5, \"drive/pid/kP\": 1, \"speed\": 2}}\n");
+        assert_eq!(error(&responses, "invalid_path")["key"], "drive/pid/kP");
+        // nothing was applied
+        assert!(table.lock().unwrap().get("speed").is_none());
+        assert_eq!(path::get(&table.lock().unwrap(), "drive/pid").unwrap().to_json(), json!({}));
+
+        let responses = feed(b"{\"type\": \"update\", \"id\": 2, \"table\": {\"drive/pid\": {}, \"drive/pid/kP\": 1}}\n");
+        assert_eq!(responses[0]["type"], "ack");
+        assert_eq!(path::get(&table.lock().unwrap(), "drive/pid/kP").unwrap().to_json(), json!(1));
+    }
+
+    #[test]
+    fn patch_needs_read_access() {
+        let (mut session, _poll) = session();
+        let table = Arc::new(Mutex::new(HashMap::from([("secret".to_string(), Data::Integer(42))])));
+        session.frames.extend(b"{\"type\": \"handshake\", \"name\": \"client\"}\n").unwrap();
+        Connection::process(&mut session, &table).unwrap();
+        session.to_client.take();
+        session.access = Access { read: Some(Vec::new()), write: None };
+
+        session.frames.extend(b"{\"type\": \"patch\", \"key\": \"secret\", \"patch\": [{\"op\": \"test\", \"path\": \"\", \"value\": 42}]}\n").unwrap();
+        Connection::process(&mut session, &table).unwrap();
+        assert_eq!(error(&session.to_client.take(), "permission_denied")["key"], "secret");
+    }
+
+    #[test]
+    fn declare_needs_read_access() {
+        let (mut session, _poll) = session();
+        let table = Arc::new(Mutex::new(HashMap::from([("secret".to_string(), Data::Integer(42))])));
+        session.frames.extend(b"{\"type\": \"handshake\", \"name\": \"client\"}\n").unwrap();
+        Connection::process(&mut session, &table).unwrap();
+        session.to_client.take();
+        session.access = Access { read: Some(Vec::new()), write: None };
+
+        session.frames.extend(b"{\"type\": \"schema\", \"schema\": {\"secret\": {\"enum\": [42]}}}\n").unwrap();
+        Connection::process(&mut session, &table).unwrap();
+        assert_eq!(error(&session.to_client.take(), "permission_denied")["key"], "secret");
+    }
+
+    #[test]
+    fn handshake_echoes_request_id() {
+        let (mut client, _poll) = session();
+        let (_, responses) = feed(&mut client, b"{\"type\": \"handshake\", \"name\": \"client\", \"id\": \"h1\"}\n");
+        assert_eq!(responses[0]["id"], "h1");
+        assert_eq!(responses[0]["client_id"], 1);
+
+        let (mut client, _poll) = session();
+        let (_, responses) = feed(&mut client, b"{\"type\": \"handshake\", \"name\": \"client\"}\n");
+        assert_eq!(responses[0]["id"], 1);
+        assert_eq!(responses[0]["client_id"], 1);
+    }
+
+    #[test]
+    fn heartbeat_needs_version_2() {
+        let (mut client, _poll) = session();
+        let (_, responses) = feed(&mut client, b"{\"type\": \"handshake\", \"name\": \"client\", \"version\": 2}\n");
+        assert!(client.features.contains(&"heartbeat"));
+        assert!(responses[0]["features"].as_array().unwrap().contains(&json!("heartbeat")));
+
+        let (mut client, _poll) = session();
+        feed(&mut client, b"{\"type\": \"handshake\", \"name\": \"client\"}\n");
+        assert!(!client.features.contains(&"heartbeat"));
+    }
+
+    #[test]
+    fn mistyped_fields() {
+        let (mut session, _poll) = session();
+        feed(&mut session, b"{\"type\": \"handshake\", \"name\": \"client\"}\n");
+        let messages: [(&[u8], &str); 7] = [
+            (b"{\"type\": \"delete\", \"keys\": \"a\"}\n", "keys"),
+            (b"{\"type\": \"patch\", \"key\": 1, \"patch\": []}\n", "key"),
+            (b"{\"type\": \"patch\", \"key\": \"a\", \"patch\": {}}\n", "patch"),
+            (b"{\"type\": \"get\", \"prefix\": 1}\n", "prefix"),
+            (b"{\"type\": \"subscribe\", \"keys\": [1]}\n", "keys"),
+            (b"{\"type\": \"schema\", \"key\": \"a\", \"schema\": 1}\n", "schema"),
+            (b"{\"type\": \"update\", \"table\": {\"a\": 1}, \"expect\": []}\n", "expect"),
+        ];
+        for (message, field) in messages {
+            let (alive, responses) = feed(&mut session, message);
+            assert!(alive);
+            assert_eq!(error(&responses, "invalid_message")["field"], field);
+        }
+    }
+
+    #[test]
+    fn invalid_json_is_skipped() {
+        let (mut session, _poll) = session();
+        let (alive, responses) = feed(&mut session, b"{\"type\": \n{\"type\": \"handshake\", \"name\": \"client\"}\n");
+        assert!(alive);
+        assert_eq!(responses.len(), 2);
+        error(&responses[..1], "invalid_json");
+        assert_eq!(responses[1]["status"], "ok");
+    }
+
+    #[test]
+    fn unknown_type() {
+        let (mut session, _poll) = session();
+        feed(&mut session, b"{\"type\": \"handshake\", \"name\": \"client\"}\n");
+        let (alive, responses) = feed(&mut session, b"{\"type\": \"teleport\"}\n");
+        assert!(alive);
+        error(&responses, "unknown_type");
+    }
+
+    #[test]
+    fn handshake_required() {
+        let (mut session, _poll) = session();
+        let (alive, responses) = feed(&mut session, b"{\"type\": \"update\", \"table\": {\"a\": 1}}\n");
+        assert!(alive);
+        error(&responses, "handshake_required");
+    }
+
+    #[test]
+    fn protocol_before_handshake() {
+        let (mut session, _poll) = session();
+        let (alive, responses) = feed(&mut session, b"{\"type\": \"protocol\", \"id\": 1}\n");
+        assert!(alive);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0]["status"], "ok");
+        assert_eq!(responses[0]["id"], 1);
+        assert_eq!(responses[0]["version"], protocol::VERSION);
+        assert_eq!(responses[0]["messages"]["update"]["fields"]["table"]["required"], true);
+        assert_eq!(responses[0]["messages"]["update"]["handshake_required"], true);
+        assert!(!session.handshake);
+    }
+
+    #[test]
+    fn version_negotiation() {
+        // a newer client is downgraded and only gets the features it knows
+        let (mut client, _poll) = session();
+        let message = format!("{{\"type\": \"handshake\", \"name\": \"client\", \"version\": {}, \"features\": [\"patch\", \"teleport\"]}}\n", protocol::VERSION + 1);
+        let (alive, responses) = feed(&mut client, message.as_bytes());
+        assert!(alive);
+        assert_eq!(responses[0]["status"], "ok");
+        assert_eq!(responses[0]["version"], protocol::VERSION);
+        assert_eq!(responses[0]["features"], json!(["patch"]));
+
+        // a client that can't go back as far as the server is turned away
+        let (mut client, _poll) = session();
+        let message = format!("{{\"type\": \"handshake\", \"name\": \"client\", \"version\": {0}, \"min_version\": {0}}}\n", protocol::VERSION + 1);
+        let (_, responses) = feed(&mut client, message.as_bytes());
+        let response = error(&responses, "unsupported_version");
+        assert_eq!(response["terminate"], true);
+        assert_eq!(response["version"], protocol::VERSION);
+        assert!(!client.handshake);
+
+        let (mut client, _poll) = session();
+        let (alive, responses) = feed(&mut client, b"{\"type\": \"handshake\", \"name\": \"client\", \"version\": \"1\"}\n");
+        assert!(alive);
+        assert_eq!(error(&responses, "invalid_message")["field"], "version");
+    }
+
+    #[test]
+    fn ping_pong() {
+        let (mut session, _poll) = session();
+        let (alive, responses) = feed(&mut session, b"{\"type\": \"ping\", \"id\": \"a\"}\n");
+        assert!(alive);
+        assert_eq!(responses, vec![json!({"type": "pong", "status": "ok", "id": "a"})]);
+
+        let (alive, responses) = feed(&mut session, b"{\"type\": \"pong\"}\n");
+        assert!(alive);
+        assert!(responses.is_empty());
+    }
+
+    #[test]
+    fn features_left_out() {
+        let (mut peer, _peer_poll) = session();
+        let (mut session, _poll) = session();
+        peer.id = 2;
+        peer.bus = session.bus.clone();
+        let table = Arc::new(Mutex::new(HashMap::new()));
+        let mut feed = |bytes: &[u8]| {
+            session.frames.extend(bytes).unwrap();
+            Connection::process(&mut session, &table).unwrap();
+            session.outgoing()
+        };
+        let responses = feed(b"{\"type\": \"handshake\", \"name\": \"client\", \"features\": [\"get\"]}\n");
+        assert_eq!(responses[0]["features"], json!(["get"]));
+        assert!(responses[0].get("versions").is_none());
+        assert!(responses[0].get("schema").is_none());
+
+        // no acknowledgement, no versions, and plain JSON in place of the float and bytes encodings
+        let responses = feed(b"{\"type\": \"update\", \"id\": 1, \"table\": {\"a\": {\"$float\": \"NaN\"}, \"b\": {\"$bytes\": \"AQI=\"}}}\n");
+        assert!(responses.is_empty());
+        let responses = feed(b"{\"type\": \"get\", \"keys\": [\"a\", \"b\"]}\n");
+        assert_eq!(responses[0]["table"], json!({"a": null, "b": "AQI="}));
+        assert!(responses[0].get("versions").is_none());
+
+        // messages of features left out are unknown
+        let responses = feed(b"{\"type\": \"patch\", \"key\": \"a\", \"patch\": []}\n");
+        assert_eq!(error(&responses, "unknown_type")["type"], "patch");
+
+        // a peer's patch arrives as the value it leaves behind, its deletes and schemas not at all
+        for message in [
+            &b"{\"type\": \"handshake\", \"name\": \"peer\"}\n"[..],
+            b"{\"type\": \"update\", \"table\": {\"c\": {\"x\": 1}}}\n",
+            b"{\"type\": \"patch\", \"key\": \"c\", \"patch\": [{\"op\": \"replace\", \"path\": \"/x\", \"value\": 2}]}\n",
+            b"{\"type\": \"delete\", \"keys\": [\"c\"]}\n",
+            b"{\"type\": \"schema\", \"schema\": {\"d\": {\"type\": \"number\"}}}\n",
+        ] {
+            peer.frames.extend(message).unwrap();
+            Connection::process(&mut peer, &table).unwrap();
+        }
+        let responses = feed(b"");
+        assert_eq!(responses, vec![
+            json!({"type": "update", "status": "ok", "table": {"c": {"x": 1}}}),
+            json!({"type": "update", "status": "ok", "table": {"c": {"x": 2}}}),
+        ]);
+    }
+
+    #[test]
+    fn oversized_frame() {
+        let (mut session, _poll) = session();
+        session.frames.set_framing(Framing::LengthPrefixed);
+        let (alive, responses) = feed(&mut session, &u32::MAX.to_be_bytes());
+        assert!(!alive);
+        assert_eq!(error(&responses, "invalid_frame")["type"], "error");
+    }
+
+    #[test]
+    fn garbage() {
+        let (mut session, _poll) = session();
+        let messages = [
+            "{}", "null", "true", "1", "\"\"", "[]", "{\"type\": null}", "{\"type\": {}}", "{\"type\": \"\"}",
+            "{\"type\": \"handshake\", \"name\": []}", "{\"type\": \"handshake\", \"name\": \"a b\"}",
+            "{\"type\": \"handshake\", \"name\": \"client\", \"framing\": 1}",
+        ];
+        for message in messages {
+            let (_, responses) = feed(&mut session, format!("{}\n", message).as_bytes());
+            assert_eq!(responses.len(), 1, "{}", message);
+            assert_eq!(responses[0]["status"], "error", "{}", message);
+        }
+    }
+}
@@ -0,0 +1,18 @@
+use std::io::Error;
+use crate::http::{self, Request};
+use crate::stream::Stream;
+
+const INDEX: &str = include_str!("dashboard.html");
+
+// serve the dashboard page for plain HTTP requests on the websocket listener
+// the page connects back to the same address over websocket
+pub fn serve(stream: &mut Stream, request: &Request) -> Result<(), Error> {
+    if request.method != "GET" {
+        return http::respond(stream, "405 Method Not Allowed", "text/plain", b"Method not allowed");
+    }
+
+    match request.path.as_str() {
+        "/" | "/index.html" => http::respond(stream, "200 OK", "text/html; charset=utf-8", INDEX.as_bytes()),
+        _ => http::respond(stream, "404 Not Found", "text/plain", b"Not found"),
+    }
+}
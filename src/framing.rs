@@ -0,0 +1,381 @@
+use std::io::{Error, ErrorKind};
+use serde_json::Value;
+use crate::websocket;
+
+// the largest single message the server will buffer for a client
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Framing {
+    // JSON values written back to back with no delimiter, e.g. { ... }{ ... }
+    // this is what clients spoke before framing was negotiated, so it stays the default
+    Raw,
+    // one JSON value per line
+    Ndjson,
+    // a 4 byte big-endian length followed by that many bytes of JSON
+    LengthPrefixed,
+    // websocket text frames, one JSON value per message
+    // only used on the websocket listener, clients can't request it in the handshake
+    WebSocket,
+}
+
+impl Framing {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "raw" => Some(Framing::Raw),
+            "ndjson" => Some(Framing::Ndjson),
+            "length" => Some(Framing::LengthPrefixed),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Framing::Raw => "raw",
+            Framing::Ndjson => "ndjson",
+            Framing::LengthPrefixed => "length",
+            Framing::WebSocket => "websocket",
+        }
+    }
+
+    pub fn encode(&self, value: &Value) -> Vec<u8> {
+        let json_string = value.to_string();
+        match self {
+            Framing::Raw => json_string.into_bytes(),
+            Framing::Ndjson => {
+                let mut bytes = json_string.into_bytes();
+                bytes.push(b'\n');
+                bytes
+            }
+            Framing::LengthPrefixed => {
+                let mut bytes = (json_string.len() as u32).to_be_bytes().to_vec();
+                bytes.extend_from_slice(json_string.as_bytes());
+                bytes
+            }
+            Framing::WebSocket => websocket::encode_frame(websocket::OPCODE_TEXT, json_string.as_bytes()),
+        }
+    }
+}
+
+// reassembles messages from whatever chunks the socket hands us
+//
+// errors with ErrorKind::InvalidData mean a single message was malformed and has been dropped,
+// any other error means the stream can't be recovered and the connection should be closed
+pub struct FrameBuffer {
+    framing: Framing,
+    buffer: Vec<u8>,
+    // payload of a fragmented websocket message that hasn't finished yet
+    fragments: Vec<u8>,
+    // control frames (pong, close) that need to be written back to the client
+    replies: Vec<u8>,
+    // how much of the buffer has already been searched for the end of the next message,
+    // so a message arriving in many reads is only scanned once rather than from the start on every read
+    scanned: usize,
+    // where a raw message's scan left off: how deeply nested it is and whether it is inside a string
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl FrameBuffer {
+    pub fn new() -> Self {
+        Self {
+            framing: Framing::Raw,
+            buffer: Vec::new(),
+            fragments: Vec::new(),
+            replies: Vec::new(),
+            scanned: 0,
+            depth: 0,
+            in_string: false,
+            escaped: false,
+        }
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    // anything still in the buffer will be decoded with the new framing
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+        self.reset_scan();
+    }
+
+    // bytes received but not yet taken out as messages
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    fn reset_scan(&mut self) {
+        self.scanned = 0;
+        self.depth = 0;
+        self.in_string = false;
+        self.escaped = false;
+    }
+
+    // bytes that have to be sent to the client before anything else, e.g. answers to websocket pings
+    pub fn take_replies(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.replies)
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        // leave room for the frame header on top of the payload
+        if self.buffer.len() + bytes.len() > MAX_FRAME_SIZE + 16 {
+            self.buffer.clear();
+            self.reset_scan();
+            return Err(Error::other("Message exceeds the maximum frame size"));
+        }
+
+        self.buffer.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    // pull the next complete message out of the buffer, if there is one
+    pub fn next_message(&mut self) -> Result<Option<Value>, Error> {
+        match self.framing {
+            Framing::Raw => self.next_raw(),
+            Framing::Ndjson => self.next_line(),
+            Framing::LengthPrefixed => self.next_length_prefixed(),
+            Framing::WebSocket => self.next_websocket(),
+        }
+    }
+
+    fn next_raw(&mut self) -> Result<Option<Value>, Error> {
+        if self.scanned == 0 {
+            let start = match self.buffer.iter().position(|b| !b.is_ascii_whitespace()) {
+                Some(start) => start,
+                None => {
+                    self.buffer.clear();
+                    return Ok(None);
+                }
+            };
+            self.buffer.drain(..start);
+        }
+
+        // find where the value starting at the front of the buffer ends, serde only sees it once it is complete
+        let end = match self.scan_raw() {
+            Some(end) => end,
+            // the rest of the message hasn't arrived yet
+            None => return Ok(None),
+        };
+        self.reset_scan();
+
+        match serde_json::from_slice(&self.buffer[..end]) {
+            Ok(json) => {
+                self.buffer.drain(..end);
+                Ok(Some(json))
+            }
+            Err(e) => {
+                // the scan only follows brackets and strings, after invalid JSON it can't be trusted
+                // to have found where the next message starts
+                self.buffer.clear();
+                Err(Error::new(ErrorKind::InvalidData, e))
+            }
+        }
+    }
+
+    // carry on scanning a raw value from where the last call stopped, returning its length once it is complete
+    // objects, arrays and strings end with their closing byte, other values at the first byte that can't be part of them
+    fn scan_raw(&mut self) -> Option<usize> {
+        let scalar = |b: u8| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'+' | b'.');
+        while self.scanned < self.buffer.len() {
+            let position = self.scanned;
+            let b = self.buffer[position];
+            self.scanned += 1;
+
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if b == b'\\' {
+                    self.escaped = true;
+                } else if b == b'"' {
+                    self.in_string = false;
+                    if self.depth == 0 {
+                        return Some(position + 1);
+                    }
+                }
+                continue;
+            }
+
+            match b {
+                // past the first byte at the top level is a number, true, false or null, which ends with its characters
+                _ if self.depth == 0 && position > 0 && !scalar(b) => return Some(position),
+                _ if self.depth == 0 && position > 0 => {}
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                // a stray closing bracket is left for serde to reject
+                b'}' | b']' if self.depth <= 1 => return Some(position + 1),
+                b'}' | b']' => self.depth -= 1,
+                // so is anything that can't start a value
+                _ if self.depth == 0 && !scalar(b) => return Some(position + 1),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    fn next_line(&mut self) -> Result<Option<Value>, Error> {
+        loop {
+            // only search what arrived since the last call
+            let end = match self.buffer[self.scanned..].iter().position(|b| *b == b'\n') {
+                Some(end) => self.scanned + end,
+                None => {
+                    self.scanned = self.buffer.len();
+                    return Ok(None);
+                }
+            };
+            self.scanned = 0;
+
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = line.trim_ascii();
+            // skip blank lines between messages
+            if line.is_empty() {
+                continue;
+            }
+
+            return match serde_json::from_slice(line) {
+                Ok(json) => Ok(Some(json)),
+                Err(e) => Err(Error::new(ErrorKind::InvalidData, e)),
+            };
+        }
+    }
+
+    fn next_length_prefixed(&mut self) -> Result<Option<Value>, Error> {
+        if self.buffer.len() < 4 {
+            return Ok(None);
+        }
+
+        let length = u32::from_be_bytes([self.buffer[0], self.buffer[1], self.buffer[2], self.buffer[3]]) as usize;
+        if length > MAX_FRAME_SIZE {
+            self.buffer.clear();
+            return Err(Error::other(format!("Frame of {} bytes exceeds the maximum frame size", length)));
+        }
+        if self.buffer.len() < 4 + length {
+            return Ok(None);
+        }
+
+        let frame: Vec<u8> = self.buffer.drain(..4 + length).skip(4).collect();
+        match serde_json::from_slice(&frame) {
+            Ok(json) => Ok(Some(json)),
+            Err(e) => Err(Error::new(ErrorKind::InvalidData, e)),
+        }
+    }
+
+    fn next_websocket(&mut self) -> Result<Option<Value>, Error> {
+        loop {
+            let (frame, used) = match websocket::parse_frame(&self.buffer, MAX_FRAME_SIZE)? {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+            self.buffer.drain(..used);
+
+            match frame.opcode {
+                websocket::OPCODE_TEXT | websocket::OPCODE_BINARY | websocket::OPCODE_CONTINUATION => {
+                    if self.fragments.len() + frame.payload.len() > MAX_FRAME_SIZE {
+                        return Err(Error::other("Websocket message exceeds the maximum frame size"));
+                    }
+                    self.fragments.extend_from_slice(&frame.payload);
+                    if !frame.fin {
+                        continue;
+                    }
+
+                    let message = std::mem::take(&mut self.fragments);
+                    return match serde_json::from_slice(&message) {
+                        Ok(json) => Ok(Some(json)),
+                        Err(e) => Err(Error::new(ErrorKind::InvalidData, e)),
+                    };
+                }
+                websocket::OPCODE_PING => {
+                    self.replies.extend(websocket::encode_frame(websocket::OPCODE_PONG, &frame.payload));
+                }
+                websocket::OPCODE_PONG => {}
+                websocket::OPCODE_CLOSE => {
+                    // echo the close frame back before dropping the connection
+                    self.replies.extend(websocket::encode_frame(websocket::OPCODE_CLOSE, &frame.payload));
+                    return Err(Error::new(ErrorKind::ConnectionAborted, "Websocket closed by client"));
+                }
+                opcode => {
+                    return Err(Error::other(format!("Unknown websocket opcode: {}", opcode)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    // feed the bytes a few at a time, taking out messages after every chunk like the connection does
+    fn feed(framing: Framing, bytes: &[u8], chunk: usize) -> Vec<Result<Value, ErrorKind>> {
+        let mut frames = FrameBuffer::new();
+        frames.set_framing(framing);
+        let mut messages = Vec::new();
+        for chunk in bytes.chunks(chunk) {
+            frames.extend(chunk).unwrap();
+            loop {
+                match frames.next_message() {
+                    Ok(Some(json)) => messages.push(Ok(json)),
+                    Ok(None) => break,
+                    Err(e) => messages.push(Err(e.kind())),
+                }
+            }
+        }
+        messages
+    }
+
+    #[test]
+    fn raw_split_reads() {
+        let bytes = br#"{"type": "update", "table": {"a": [1, 2, {"b": null}]}} [true] "text" {"c": 1}"#;
+        let expected = vec![
+            Ok(json!({"type": "update", "table": {"a": [1, 2, {"b": null}]}})),
+            Ok(json!([true])),
+            Ok(json!("text")),
+            Ok(json!({"c": 1})),
+        ];
+        for chunk in [1, 2, 3, 7, bytes.len()] {
+            assert_eq!(feed(Framing::Raw, bytes, chunk), expected, "chunks of {}", chunk);
+        }
+    }
+
+    #[test]
+    fn raw_brackets_in_strings() {
+        let bytes = br#"{"a": "}{"}{"b": "\"}{\\", "c": "]["}"#;
+        let expected = vec![Ok(json!({"a": "}{"})), Ok(json!({"b": "\"}{\\", "c": "]["}))];
+        for chunk in [1, 4, bytes.len()] {
+            assert_eq!(feed(Framing::Raw, bytes, chunk), expected, "chunks of {}", chunk);
+        }
+    }
+
+    #[test]
+    fn raw_scalars_end_at_a_delimiter() {
+        // a number can't be known to be complete until something that isn't part of it arrives
+        assert_eq!(feed(Framing::Raw, b"12", 1), vec![]);
+        assert_eq!(feed(Framing::Raw, b"12 true{}", 1), vec![Ok(json!(12)), Ok(json!(true)), Ok(json!({}))]);
+    }
+
+    #[test]
+    fn raw_invalid() {
+        assert_eq!(feed(Framing::Raw, b"}{\"a\": 1}", 1)[0], Err(ErrorKind::InvalidData));
+        assert_eq!(feed(Framing::Raw, b"{\"a\": }{\"b\": 1}", 100), vec![Err(ErrorKind::InvalidData)]);
+    }
+
+    #[test]
+    fn ndjson_split_reads() {
+        let bytes = b"{\"a\": \"x\\ny\"}\n\n  [1]\r\nnot json\n{\"b\": 2}\n";
+        let expected = vec![Ok(json!({"a": "x\ny"})), Ok(json!([1])), Err(ErrorKind::InvalidData), Ok(json!({"b": 2}))];
+        for chunk in [1, 5, bytes.len()] {
+            assert_eq!(feed(Framing::Ndjson, bytes, chunk), expected, "chunks of {}", chunk);
+        }
+    }
+
+    #[test]
+    fn length_prefixed_split_reads() {
+        let mut bytes = Framing::LengthPrefixed.encode(&json!({"a": "}{"}));
+        bytes.extend(Framing::LengthPrefixed.encode(&json!([1])));
+        for chunk in [1, 3, bytes.len()] {
+            assert_eq!(feed(Framing::LengthPrefixed, &bytes, chunk), vec![Ok(json!({"a": "}{"})), Ok(json!([1]))]);
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::time::Duration;
+use crate::stream::Stream;
+
+const MAX_REQUEST_SIZE: usize = 8192;
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    // header names are lowercased
+    pub headers: HashMap<String, String>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_ascii_lowercase()).map(|value| value.as_str())
+    }
+}
+
+// read an HTTP request head from a freshly accepted socket
+// returns the request and any bytes the client sent after it
+pub fn read_request(stream: &mut Stream) -> Result<(Request, Vec<u8>), Error> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    let header_end = loop {
+        if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if request.len() > MAX_REQUEST_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData, "HTTP request too large"));
+        }
+
+        let bytes_read = stream.read(&mut buffer)?;
+        if bytes_read == 0 {
+            return Err(Error::new(ErrorKind::ConnectionAborted, "Connection closed during HTTP request"));
+        }
+        request.extend_from_slice(&buffer[..bytes_read]);
+    };
+
+    stream.set_read_timeout(None)?;
+
+    let head = String::from_utf8_lossy(&request[..header_end]).to_string();
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let method = request_line.next().unwrap_or("").to_string();
+    let path = match request_line.next() {
+        Some(path) => path.to_string(),
+        None => return Err(Error::new(ErrorKind::InvalidData, "Malformed HTTP request line")),
+    };
+
+    let mut headers = HashMap::new();
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+
+    Ok((Request { method, path, headers }, request[header_end..].to_vec()))
+}
+
+pub fn respond(stream: &mut Stream, status: &str, content_type: &str, body: &[u8]) -> Result<(), Error> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()
+}
@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Error, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use serde_json::{json, Value};
+use crate::logger::{log_error, log_warn};
+use crate::path;
+use crate::utils::{now_millis, Data, JSON};
+
+// an append-only log of every change made to the table, one JSON object per line:
+// {"time": 1714566600250, "client": 3, "name": "robot", "op": "set", "key": "speed", "value": 1.5}
+// {"time": 1714566601000, "client": null, "name": "console", "op": "delete", "key": "speed"}
+// client is null for changes made from the server console
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+pub struct Entry {
+    pub time: u64,
+    pub client: Option<usize>,
+    pub name: String,
+    pub key: String,
+    // None for a deletion
+    pub value: Option<Value>,
+}
+
+impl Journal {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&self, client: Option<usize>, name: &str, key: &str, value: &Value) {
+        self.write(json!({
+            "time": now_millis(),
+            "client": client,
+            "name": name,
+            "op": "set",
+            "key": key,
+            "value": value,
+        }));
+    }
+
+    pub fn record_delete(&self, client: Option<usize>, name: &str, key: &str) {
+        self.write(json!({
+            "time": now_millis(),
+            "client": client,
+            "name": name,
+            "op": "delete",
+            "key": key,
+        }));
+    }
+
+    fn write(&self, entry: Value) {
+        let mut line = entry.to_string();
+        line.push('\n');
+
+        // write the whole line at once so a crash can at worst leave a partial final line
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file.write_all(line.as_bytes()).and_then(|_| file.flush()) {
+            log_error!("Failed to write to journal {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+pub fn read(path: &Path) -> Result<Vec<Entry>, Error> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match parse_entry(&line) {
+            Some(entry) => entries.push(entry),
+            None => log_warn!("Skipping malformed journal entry on line {} of {}", number + 1, path.display()),
+        }
+    }
+
+    Ok(entries)
+}
+
+fn parse_entry(line: &str) -> Option<Entry> {
+    let json: Value = serde_json::from_str(line).ok()?;
+    let value = match json.get("op").and_then(|op| op.as_str()).unwrap_or("set") {
+        "set" => Some(json.get("value")?.clone()),
+        "delete" => None,
+        _ => return None,
+    };
+    Some(Entry {
+        time: json.get("time")?.as_u64()?,
+        client: json.get("client").and_then(|client| client.as_u64()).map(|client| client as usize),
+        name: json.get("name").and_then(|name| name.as_str()).unwrap_or("").to_string(),
+        key: json.get("key")?.as_str()?.to_string(),
+        value,
+    })
+}
+
+// apply entries in the range since..=until to the table, returning how many were applied
+pub fn replay(entries: &[Entry], table: &mut HashMap<String, Data>, since: Option<u64>, until: Option<u64>) -> usize {
+    let mut applied = 0;
+    for entry in entries {
+        if since.is_some_and(|since| entry.time < since) || until.is_some_and(|until| entry.time > until) {
+            continue;
+        }
+        // keys are paths, replaying them in order rebuilds nested objects the same way they were built
+        match &entry.value {
+            Some(value) => {
+                if let Err(e) = path::set(table, &entry.key, Data::from_json(value)) {
+                    log_warn!("Skipping journal entry for {}: {}", entry.key, e);
+                    continue;
+                }
+            }
+            None => {
+                path::remove(table, &entry.key);
+            }
+        }
+        applied += 1;
+    }
+    applied
+}
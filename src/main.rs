@@ -1,25 +1,160 @@
 mod server;
 mod utils;
+mod framing;
+mod websocket;
+mod http;
+mod dashboard;
 mod connection;
 mod command_manager;
 mod commands;
+mod cli;
+mod config;
+mod auth;
+mod logger;
+mod persistence;
+mod journal;
+mod path;
+mod patch;
+mod protocol;
+mod schema;
+mod bus;
+mod stream;
+mod tls;
+mod versions;
 
+use std::sync::Arc;
+use config::Config;
+use journal::Journal;
 use server::Server;
+use utils::JSON;
 use command_manager::CommandManager;
 
 fn main() {
-    let server = Server::with_address("127.0.0.1", 8080);
-    let mut commands = CommandManager::new(&server.table, &server.connections, server.address);
+    let args = match cli::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("Run with --help for usage");
+            std::process::exit(2);
+        }
+    };
+    
+    if args.help {
+        cli::print_help();
+        return;
+    }
+    
+    let config = match Config::resolve(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    
+    logger::set_level(config.log_level);
+    
+    let state = config.state.clone();
+    let console = config.console;
+    let mut server = Server::with_config(config);
+    
+    // pick up where the last run left off, an explicit --state still wins over the snapshot
+    let mut snapshot_time = None;
+    if let Some(path) = &server.config.persistence {
+        if path.exists() {
+            match persistence::load(&server.table, path) {
+                Ok(values) => logger::log_info!("Restored {} keys from {}", values.len(), path.display()),
+                Err(e) => {
+                    eprintln!("Failed to restore table from {}: {}", path.display(), e);
+                    std::process::exit(1);
+                }
+            }
+            snapshot_time = persistence::modified_millis(path);
+        }
+        persistence::start(
+            server.table.clone(),
+            path.clone(),
+            std::time::Duration::from_secs(server.config.persistence_interval),
+        );
+    }
+    
+    // replay anything journaled after the snapshot was taken, or the whole journal if there is no snapshot
+    // every change is journaled, so replaying a little before the snapshot to be safe is harmless
+    if let Some(path) = server.config.journal.clone() {
+        if path.exists() {
+            match journal::read(&path) {
+                Ok(entries) => {
+                    let since = snapshot_time.map(|time| time.saturating_sub(1000));
+                    let applied = journal::replay(&entries, &mut server.table.lock().unwrap(), since, None);
+                    logger::log_info!("Replayed {} journal entries from {}", applied, path.display());
+                }
+                Err(e) => {
+                    eprintln!("Failed to read journal {}: {}", path.display(), e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        
+        match Journal::open(&path) {
+            Ok(journal) => server.journal = Some(Arc::new(journal)),
+            Err(e) => {
+                eprintln!("Failed to open journal {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+    
+    if let Some(state) = &state {
+        match persistence::load(&server.table, state) {
+            Ok(values) => logger::log_info!("Loaded {} keys from {}", values.len(), state.display()),
+            Err(e) => {
+                eprintln!("Failed to load state from {}: {}", state.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+    
+    // whatever was restored has to match the schemas from the config, as anything loaded later does
+    let errors = server.schemas.check_all(server.table.lock().unwrap().iter().map(|(key, value)| (key, value.to_json())));
+    if !errors.is_empty() {
+        for e in &errors {
+            eprintln!("{}", e);
+        }
+        eprintln!("The table doesn't match the schemas in the config");
+        std::process::exit(1);
+    }
+    
+    // everything restored at startup starts out with a version, written by the server
+    server.versions.reset(&server.table.lock().unwrap(), None);
+    
+    if let Err(e) = server.start() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    
+    if !console {
+        // the listeners run on their own threads, keep the process alive for them
+        loop {
+            std::thread::park();
+        }
+    }
+    
+    let mut commands = CommandManager::new(&server.table, &server.connections, &server.config, &server.journal, &server.bus, &server.schemas, &server.versions);
     
     commands.add(commands::help());
     commands.add(commands::exit());
     commands.add(commands::clear());
     commands.add(commands::connections());
     commands.add(commands::status());
+    commands.add(commands::config());
     commands.add(commands::table());
     commands.add(commands::terminate());
-    
-    server.start();
+    commands.add(commands::save());
+    commands.add(commands::load());
+    commands.add(commands::set());
+    commands.add(commands::del());
+    commands.add(commands::rename());
+    commands.add(commands::replay());
     
     loop {
         // wait for input from the user
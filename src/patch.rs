@@ -0,0 +1,208 @@
+use serde_json::Value;
+use crate::utils::{json_equal, Data, JSON};
+
+// JSON Patch (RFC 6902) applied to a value in the table, so a client can change one element of a large array
+// without sending the whole thing, e.g. [{"op": "replace", "path": "/3", "value": 1.5}]
+//
+// operations are applied in order to a copy of the value, which only replaces the stored value if all of them succeed
+
+// apply every operation in the patch, returning the patched value or a message naming the operation that failed
+pub fn apply(data: &Data, patch: &[Value]) -> Result<Data, String> {
+    let mut data = data.clone();
+    for (index, operation) in patch.iter().enumerate() {
+        apply_operation(&mut data, operation).map_err(|e| format!("Operation {}: {}", index, e))?;
+    }
+    Ok(data)
+}
+
+fn apply_operation(data: &mut Data, operation: &Value) -> Result<(), String> {
+    let op = operation.get("op").and_then(|op| op.as_str()).ok_or("Missing op")?;
+    let path = pointer(operation, "path")?;
+
+    match op {
+        "add" => add(data, &path, Data::from_json(value(operation)?)),
+        "remove" => remove(data, &path).map(|_| ()),
+        "replace" => {
+            let target = get_mut(data, &path).ok_or_else(|| format!("No such path: {}", join(&path)))?;
+            *target = Data::from_json(value(operation)?);
+            Ok(())
+        }
+        "move" => {
+            let from = pointer(operation, "from")?;
+            // a value can't be moved into one of its own children
+            if path.len() > from.len() && path.starts_with(&from) {
+                return Err(format!("Can't move {} into itself", join(&from)));
+            }
+            let moved = remove(data, &from)?;
+            add(data, &path, moved)
+        }
+        "copy" => {
+            let from = pointer(operation, "from")?;
+            let copied = get_mut(data, &from).ok_or_else(|| format!("No such path: {}", join(&from)))?.clone();
+            add(data, &path, copied)
+        }
+        "test" => {
+            let target = get_mut(data, &path).ok_or_else(|| format!("No such path: {}", join(&path)))?;
+            match json_equal(&target.to_json(), value(operation)?) {
+                true => Ok(()),
+                false => Err(format!("Test failed: {}", join(&path))),
+            }
+        }
+        _ => Err(format!("Unknown op: {}", op)),
+    }
+}
+
+fn value(operation: &Value) -> Result<&Value, String> {
+    operation.get("value").ok_or_else(|| "Missing value".to_string())
+}
+
+// parse a JSON pointer (RFC 6901) field of an operation into its reference tokens, "" is the whole value
+fn pointer(operation: &Value, field: &str) -> Result<Vec<String>, String> {
+    let pointer = operation.get(field).and_then(|pointer| pointer.as_str()).ok_or(format!("Missing {}", field))?;
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    if !pointer.starts_with('/') {
+        return Err(format!("Invalid pointer: {}", pointer));
+    }
+    Ok(pointer[1..].split('/').map(|token| token.replace("~1", "/").replace("~0", "~")).collect())
+}
+
+fn join(path: &[String]) -> String {
+    path.iter().map(|token| format!("/{}", token.replace('~', "~0").replace('/', "~1"))).collect()
+}
+
+// an array index, without leading zeros as the RFC requires
+fn index(token: &str) -> Option<usize> {
+    if token.is_empty() || (token.len() > 1 && token.starts_with('0')) || !token.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    token.parse().ok()
+}
+
+fn get_mut<'a>(data: &'a mut Data, path: &[String]) -> Option<&'a mut Data> {
+    let mut data = data;
+    for token in path {
+        data = match data {
+            Data::Object(object) => object.get_mut(token)?,
+            Data::Array(array) => array.get_mut(index(token)?)?,
+            _ => return None,
+        };
+    }
+    Some(data)
+}
+
+fn add(data: &mut Data, path: &[String], value: Data) -> Result<(), String> {
+    let (last, parent) = match path.split_last() {
+        Some(split) => split,
+        None => {
+            *data = value;
+            return Ok(());
+        }
+    };
+
+    match get_mut(data, parent) {
+        Some(Data::Object(object)) => {
+            object.insert(last.clone(), value);
+            Ok(())
+        }
+        Some(Data::Array(array)) => {
+            // "-" appends to the end
+            let index = match last.as_str() {
+                "-" => array.len(),
+                _ => index(last).filter(|index| *index <= array.len()).ok_or_else(|| format!("Index out of range: {}", join(path)))?,
+            };
+            array.insert(index, value);
+            Ok(())
+        }
+        Some(_) => Err(format!("Not an object or array: {}", join(parent))),
+        None => Err(format!("No such path: {}", join(parent))),
+    }
+}
+
+fn remove(data: &mut Data, path: &[String]) -> Result<Data, String> {
+    let (last, parent) = path.split_last().ok_or("Can't remove the whole value")?;
+
+    let removed = match get_mut(data, parent) {
+        Some(Data::Object(object)) => object.remove(last),
+        Some(Data::Array(array)) => index(last).filter(|index| *index < array.len()).map(|index| array.remove(index)),
+        _ => None,
+    };
+    removed.ok_or_else(|| format!("No such path: {}", join(path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn patch(value: Value, patch: Value) -> Result<Value, String> {
+        apply(&Data::from_json(&value), patch.as_array().unwrap()).map(|data| data.to_json())
+    }
+
+    #[test]
+    fn add() {
+        assert_eq!(patch(json!({"a": 1}), json!([{"op": "add", "path": "/b", "value": 2}])), Ok(json!({"a": 1, "b": 2})));
+        assert_eq!(patch(json!([1, 3]), json!([{"op": "add", "path": "/1", "value": 2}])), Ok(json!([1, 2, 3])));
+        assert_eq!(patch(json!([1, 2]), json!([{"op": "add", "path": "/-", "value": 3}])), Ok(json!([1, 2, 3])));
+        assert_eq!(patch(json!(1), json!([{"op": "add", "path": "", "value": [2]}])), Ok(json!([2])));
+        assert!(patch(json!([1]), json!([{"op": "add", "path": "/2", "value": 3}])).is_err());
+        assert!(patch(json!([1]), json!([{"op": "add", "path": "/01", "value": 3}])).is_err());
+        assert!(patch(json!({}), json!([{"op": "add", "path": "/a/b", "value": 1}])).is_err());
+    }
+
+    #[test]
+    fn remove() {
+        assert_eq!(patch(json!({"a": 1, "b": 2}), json!([{"op": "remove", "path": "/a"}])), Ok(json!({"b": 2})));
+        assert_eq!(patch(json!([1, 2, 3]), json!([{"op": "remove", "path": "/1"}])), Ok(json!([1, 3])));
+        assert!(patch(json!({"a": 1}), json!([{"op": "remove", "path": "/b"}])).is_err());
+        assert!(patch(json!({"a": 1}), json!([{"op": "remove", "path": ""}])).is_err());
+    }
+
+    #[test]
+    fn replace() {
+        assert_eq!(patch(json!({"a": [1, 2]}), json!([{"op": "replace", "path": "/a/0", "value": 5}])), Ok(json!({"a": [5, 2]})));
+        assert!(patch(json!({"a": 1}), json!([{"op": "replace", "path": "/b", "value": 5}])).is_err());
+    }
+
+    #[test]
+    fn move_and_copy() {
+        assert_eq!(patch(json!({"a": 1}), json!([{"op": "move", "from": "/a", "path": "/b"}])), Ok(json!({"b": 1})));
+        assert_eq!(patch(json!({"a": [1]}), json!([{"op": "copy", "from": "/a", "path": "/b"}])), Ok(json!({"a": [1], "b": [1]})));
+        assert_eq!(patch(json!([1, 2, 3]), json!([{"op": "move", "from": "/0", "path": "/-"}])), Ok(json!([2, 3, 1])));
+        // a value can't go inside itself, though moving it onto itself is allowed
+        assert!(patch(json!({"a": {"b": 1}}), json!([{"op": "move", "from": "/a", "path": "/a/c"}])).is_err());
+        assert_eq!(patch(json!({"a": 1}), json!([{"op": "move", "from": "/a", "path": "/a"}])), Ok(json!({"a": 1})));
+        assert!(patch(json!({}), json!([{"op": "copy", "from": "/a", "path": "/b"}])).is_err());
+    }
+
+    #[test]
+    fn test_op() {
+        assert!(patch(json!({"a": [1, "x"]}), json!([{"op": "test", "path": "/a", "value": [1, "x"]}])).is_ok());
+        assert!(patch(json!({"a": 1}), json!([{"op": "test", "path": "/a", "value": 2}])).is_err());
+        assert!(patch(json!({"a": 1}), json!([{"op": "test", "path": "/b", "value": 1}])).is_err());
+        // numbers are equal by value however they are written
+        assert!(patch(json!({"a": 1}), json!([{"op": "test", "path": "/a", "value": 1.0}])).is_ok());
+        assert!(patch(json!({"a": 2.0}), json!([{"op": "test", "path": "/a", "value": 2}])).is_ok());
+        assert!(patch(json!({"a": {"b": [2.0]}}), json!([{"op": "test", "path": "", "value": {"a": {"b": [2]}}}])).is_ok());
+    }
+
+    #[test]
+    fn pointer_escaping() {
+        let value = json!({"a/b": 1, "m~n": 2});
+        assert!(patch(value.clone(), json!([{"op": "test", "path": "/a~1b", "value": 1}])).is_ok());
+        assert!(patch(value.clone(), json!([{"op": "test", "path": "/m~0n", "value": 2}])).is_ok());
+        assert_eq!(patch(json!({}), json!([{"op": "add", "path": "/~01", "value": 3}])), Ok(json!({"~1": 3})));
+        assert!(patch(value, json!([{"op": "test", "path": "a", "value": 1}])).is_err());
+    }
+
+    #[test]
+    fn atomic() {
+        let data = Data::from_json(&json!({"a": 1}));
+        let operations = [json!({"op": "add", "path": "/b", "value": 2}), json!({"op": "remove", "path": "/c"})];
+        let error = apply(&data, &operations).map(|data| data.to_json()).unwrap_err();
+        assert!(error.starts_with("Operation 1:"), "{}", error);
+        assert_eq!(data.to_json(), json!({"a": 1}));
+        assert!(apply(&data, &[json!({"op": "nope", "path": ""})]).is_err());
+    }
+}
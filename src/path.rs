@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use crate::utils::Data;
+
+// keys can reach into nested objects with slash separated paths, e.g. "drive/pid/kP"
+//
+// a key that exists as written always means that top-level key, so flat keys containing slashes keep working,
+// otherwise the longest prefix naming a top-level object is the root and the rest of the path is followed inside it
+// a path with no such prefix is an ordinary top-level key
+pub const SEPARATOR: char = '/';
+
+// split a key into the top-level key it lives under and the fields to follow from there
+fn resolve<'a>(table: &HashMap<String, Data>, path: &'a str) -> (&'a str, Vec<&'a str>) {
+    if table.contains_key(path) {
+        return (path, Vec::new());
+    }
+
+    for (index, _) in path.rmatch_indices(SEPARATOR) {
+        if let Some(Data::Object(_)) = table.get(&path[..index]) {
+            return (&path[..index], path[index + 1..].split(SEPARATOR).collect());
+        }
+    }
+
+    (path, Vec::new())
+}
+
+// the top-level key a path lives under
+pub fn root<'a>(table: &HashMap<String, Data>, path: &'a str) -> &'a str {
+    resolve(table, path).0
+}
+
+pub fn get<'a>(table: &'a HashMap<String, Data>, path: &str) -> Option<&'a Data> {
+    let (root, fields) = resolve(table, path);
+    let mut data = table.get(root)?;
+    for field in fields {
+        match data {
+            Data::Object(object) => data = object.get(field)?,
+            _ => return None,
+        }
+    }
+    Some(data)
+}
+
+// check a value could be set at the path without changing anything
+pub fn check_set(table: &HashMap<String, Data>, path: &str) -> Result<(), String> {
+    let (root, fields) = resolve(table, path);
+    let mut data = match table.get(root) {
+        Some(data) => data,
+        None => return Ok(()),
+    };
+
+    // fields that don't exist yet are created as objects, but something that isn't an object can't be followed
+    for (depth, field) in fields.iter().enumerate() {
+        match data {
+            Data::Object(object) => match object.get(*field) {
+                Some(next) => data = next,
+                None => return Ok(()),
+            },
+            _ => return Err(format!("Not an object: {}", parent(root, &fields[..depth]))),
+        }
+    }
+    Ok(())
+}
+
+// check a batch of values could be set one after another without changing anything, each path followed
+// through the values set before it, returning the first path that can't be set and why
+pub fn check_set_all(table: &HashMap<String, Data>, values: &[(&str, Data)]) -> Result<(), (String, String)> {
+    // only a value set earlier at a path above this one can change how it is followed
+    let nested = values.iter().enumerate()
+        .any(|(index, (path, _))| ancestors(path).skip(1).any(|above| values[..index].iter().any(|(earlier, _)| *earlier == above)));
+    if !nested {
+        for (path, _) in values {
+            check_set(table, path).map_err(|e| (path.to_string(), e))?;
+        }
+        return Ok(());
+    }
+
+    // otherwise try them out on copies of the top-level keys the paths could resolve to
+    let mut scratch: HashMap<String, Data> = values.iter()
+        .flat_map(|(path, _)| ancestors(path))
+        .filter_map(|key| table.get(key).map(|data| (key.to_string(), data.clone())))
+        .collect();
+    for (path, value) in values {
+        set(&mut scratch, path, value.clone()).map_err(|e| (path.to_string(), e))?;
+    }
+    Ok(())
+}
+
+// set the value at the path, creating missing objects along the way
+// nothing is changed if the path runs into something that isn't an object
+pub fn set(table: &mut HashMap<String, Data>, path: &str, value: Data) -> Result<(), String> {
+    let (root, fields) = resolve(table, path);
+    let (last, fields) = match fields.split_last() {
+        Some((last, fields)) => (*last, fields),
+        None => {
+            table.insert(root.to_string(), value);
+            return Ok(());
+        }
+    };
+
+    // resolve only returns fields under a root that exists
+    let mut data = match table.get_mut(root) {
+        Some(data) => data,
+        None => return Err(format!("No such key: {}", root)),
+    };
+    for (depth, field) in fields.iter().enumerate() {
+        match data {
+            Data::Object(object) => data = object.entry(field.to_string()).or_insert_with(|| Data::Object(HashMap::new())),
+            _ => return Err(format!("Not an object: {}", parent(root, &fields[..depth]))),
+        }
+    }
+    match data {
+        Data::Object(object) => {
+            object.insert(last.to_string(), value);
+            Ok(())
+        }
+        _ => Err(format!("Not an object: {}", parent(root, fields))),
+    }
+}
+
+pub fn remove(table: &mut HashMap<String, Data>, path: &str) -> Option<Data> {
+    let (root, fields) = resolve(table, path);
+    let (last, fields) = match fields.split_last() {
+        Some((last, fields)) => (*last, fields),
+        None => return table.remove(root),
+    };
+
+    let mut data = table.get_mut(root)?;
+    for field in fields {
+        match data {
+            Data::Object(object) => data = object.get_mut(*field)?,
+            _ => return None,
+        }
+    }
+    match data {
+        Data::Object(object) => object.remove(last),
+        _ => None,
+    }
+}
+
+// the path of the object holding the first `fields` fields under the root
+fn parent(root: &str, fields: &[&str]) -> String {
+    let mut path = root.to_string();
+    for field in fields {
+        path.push(SEPARATOR);
+        path.push_str(field);
+    }
+    path
+}
+
+// the path and every path above it, e.g. "a/b/c", "a/b", "a"
+pub fn ancestors(path: &str) -> impl Iterator<Item = &str> {
+    std::iter::once(path).chain(path.rmatch_indices(SEPARATOR).map(move |(index, _)| &path[..index]))
+}
@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde_json::Value;
+use crate::logger::{log_debug, log_error};
+use crate::utils::{Data, JSON};
+
+pub fn snapshot(table: &HashMap<String, Data>) -> Value {
+    Value::Object(table.iter().map(|(key, value)| (key.clone(), value.to_json())).collect())
+}
+
+// write the table to disk, returning the number of keys saved
+pub fn save(table: &Arc<Mutex<HashMap<String, Data>>>, path: &Path) -> Result<usize, Error> {
+    let (json, count) = {
+        let table = table.lock().unwrap();
+        (snapshot(&table), table.len())
+    };
+
+    write_atomic(path, serde_json::to_string_pretty(&json)?.as_bytes())?;
+    Ok(count)
+}
+
+// write to a temporary file next to the target and rename it over the top,
+// so a crash mid-write never leaves a truncated snapshot behind
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+
+    let mut file = File::create(&temp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+
+    std::fs::rename(&temp, path)
+}
+
+// when the file was last written, in milliseconds since the epoch
+pub fn modified_millis(path: &Path) -> Option<u64> {
+    let modified = std::fs::metadata(path).ok()?.modified().ok()?;
+    Some(modified.duration_since(std::time::UNIX_EPOCH).ok()?.as_millis() as u64)
+}
+
+// read a JSON object from a file
+pub fn read(path: &Path) -> Result<serde_json::Map<String, Value>, Error> {
+    let contents = std::fs::read_to_string(path)?;
+    let json: Value = serde_json::from_str(&contents).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+    match json {
+        Value::Object(object) => Ok(object),
+        _ => Err(Error::new(ErrorKind::InvalidData, "Expected a JSON object")),
+    }
+}
+
+// read a JSON object from a file into the table, replacing any keys it contains
+// returns the values that were loaded
+pub fn load(table: &Arc<Mutex<HashMap<String, Data>>>, path: &Path) -> Result<serde_json::Map<String, Value>, Error> {
+    let object = read(path)?;
+
+    let mut table = table.lock().unwrap();
+    for (key, value) in &object {
+        table.insert(key.clone(), Data::from_json(value));
+    }
+
+    Ok(object)
+}
+
+// periodically snapshot the table, skipping the write when nothing has changed
+pub fn start(table: Arc<Mutex<HashMap<String, Data>>>, path: PathBuf, interval: Duration) {
+    std::thread::spawn(move || {
+        let mut last = None;
+        loop {
+            std::thread::sleep(interval);
+
+            let json = snapshot(&table.lock().unwrap());
+            if last.as_ref() == Some(&json) {
+                continue;
+            }
+
+            match serde_json::to_string_pretty(&json).map_err(Error::from).and_then(|contents| write_atomic(&path, contents.as_bytes())) {
+                Ok(_) => {
+                    log_debug!("Saved table to {}", path.display());
+                    last = Some(json);
+                }
+                Err(e) => log_error!("Failed to save table to {}: {}", path.display(), e),
+            }
+        }
+    });
+}
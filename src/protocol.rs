@@ -0,0 +1,352 @@
+use serde_json::{json, Map, Value};
+use crate::utils::untag;
+
+// the newest protocol version this server speaks, bumped whenever the message set changes in a way old clients can't follow
+pub const VERSION: u64 = 2;
+// the oldest version a client may still ask for
+pub const MIN_VERSION: u64 = 1;
+
+// optional parts of the protocol, so a client can tell what a server supports without trying it,
+// each with the version it arrived in so a client asking for an older version doesn't get it
+pub const FEATURES: &[(&str, u64)] = &[
+    ("framing", 1), ("subscribe", 1), ("get", 1), ("paths", 1), ("patch", 1), ("schema", 1), ("delete", 1),
+    ("versions", 1), ("expect", 1), ("ack", 1), ("binary", 1),
+    // clients have to answer pings, which those written for version 1 don't know to do
+    ("heartbeat", 2),
+];
+
+// every message type a client can send, with its fields as (name, type, required)
+// field types are the schema type names, or "any"; every message may also carry an "id"
+struct MessageType {
+    name: &'static str,
+    description: &'static str,
+    // whether it can be sent before the handshake
+    anonymous: bool,
+    // the feature a client has to negotiate to send it
+    feature: Option<&'static str>,
+    fields: &'static [(&'static str, &'static str, bool)],
+}
+
+const MESSAGES: &[MessageType] = &[
+    MessageType {
+        name: "protocol",
+        description: "Describe the protocol versions, features and messages the server supports",
+        anonymous: true,
+        feature: None,
+        fields: &[],
+    },
+    MessageType {
+        name: "ping",
+        description: "Check the connection is alive, answered with a pong",
+        anonymous: true,
+        feature: None,
+        fields: &[],
+    },
+    MessageType {
+        name: "pong",
+        description: "Answer a ping from the server",
+        anonymous: true,
+        feature: None,
+        fields: &[],
+    },
+    MessageType {
+        name: "handshake",
+        description: "Identify the client and negotiate the protocol version and framing",
+        anonymous: true,
+        feature: None,
+        fields: &[("name", "string", true), ("password", "string", false), ("token", "string", false), ("framing", "string", false),
+            ("version", "integer", false), ("min_version", "integer", false), ("features", "array", false)],
+    },
+    MessageType {
+        name: "update",
+        description: "Set the values of keys or paths",
+        anonymous: false,
+        feature: None,
+        fields: &[("table", "object", true), ("expect", "object", false)],
+    },
+    MessageType {
+        name: "delete",
+        description: "Remove keys from the table",
+        anonymous: false,
+        feature: Some("delete"),
+        fields: &[("keys", "array", true), ("expect", "object", false)],
+    },
+    MessageType {
+        name: "patch",
+        description: "Apply a JSON Patch to the value of a key",
+        anonymous: false,
+        feature: Some("patch"),
+        fields: &[("key", "string", true), ("patch", "array", true), ("expect", "object", false)],
+    },
+    MessageType {
+        name: "schema",
+        description: "Declare or remove the schemas of keys",
+        anonymous: false,
+        feature: Some("schema"),
+        fields: &[("schema", "object", true)],
+    },
+    MessageType {
+        name: "get",
+        description: "Read the current values of keys or of every key with a prefix",
+        anonymous: false,
+        feature: Some("get"),
+        fields: &[("keys", "array", false), ("prefix", "string", false)],
+    },
+    MessageType {
+        name: "subscribe",
+        description: "Limit updates to keys matching the given names or patterns",
+        anonymous: false,
+        feature: Some("subscribe"),
+        fields: &[("keys", "array", true)],
+    },
+    MessageType {
+        name: "unsubscribe",
+        description: "Remove subscribed patterns, or every one without keys",
+        anonymous: false,
+        feature: Some("subscribe"),
+        fields: &[("keys", "array", false)],
+    },
+];
+
+// whether a message of this type can be sent before the handshake
+pub fn is_anonymous(request_type: &str) -> bool {
+    MESSAGES.iter().any(|message| message.name == request_type && message.anonymous)
+}
+
+// the feature a client has to have negotiated to send a message of this type, if any
+pub fn feature(request_type: &str) -> Option<&'static str> {
+    MESSAGES.iter().find(|message| message.name == request_type).and_then(|message| message.feature)
+}
+
+// everything a client needs to know to talk to this server, as returned for a "protocol" message
+pub fn describe() -> Value {
+    let messages: Map<String, Value> = MESSAGES.iter().map(|message| {
+        let mut fields: Map<String, Value> = message.fields.iter()
+            .map(|(name, kind, required)| (name.to_string(), json!({"type": kind, "required": required})))
+            .collect();
+        fields.insert("id".to_string(), json!({"type": "any", "required": false}));
+        (message.name.to_string(), json!({
+            "description": message.description,
+            "handshake_required": !message.anonymous,
+            "fields": fields
+        }))
+    }).collect();
+    json!({
+        "version": VERSION,
+        "min_version": MIN_VERSION,
+        "features": FEATURES.iter().map(|(feature, _)| *feature).collect::<Vec<_>>(),
+        "messages": messages,
+        "errors": ErrorCode::ALL.iter().map(|code| code.name()).collect::<Vec<_>>()
+    })
+}
+
+// settle the protocol version and features for a handshake
+// a client sends the newest version it speaks and may send the oldest it accepts, and gets the newest both sides speak,
+// clients that don't send a version are taken to speak the first one, as they predate versioning
+// a client listing the features it understands gets the ones both sides have, otherwise it gets all of its version's
+pub fn negotiate(json: &Value) -> Result<(u64, Vec<&'static str>), ProtocolError> {
+    let version = |field: &str| match json.get(field) {
+        Some(version) => version.as_u64().filter(|version| *version > 0)
+            .map(Some)
+            .ok_or_else(|| ProtocolError::invalid(field, format!("Expected {} as a positive integer", field))),
+        None => Ok(None),
+    };
+    let newest = version("version")?.unwrap_or(1);
+    let oldest = version("min_version")?.unwrap_or(1);
+    if oldest > newest {
+        return Err(ProtocolError::invalid("min_version", "Expected min_version to be at most version"));
+    }
+
+    let unsupported = || ProtocolError::new(ErrorCode::UnsupportedVersion, format!("Protocol versions {} to {} are supported", MIN_VERSION, VERSION))
+        .with("version", VERSION)
+        .with("min_version", MIN_VERSION);
+    let version = newest.min(VERSION);
+    if version < MIN_VERSION || version < oldest {
+        return Err(unsupported());
+    }
+
+    let features = match json.get("features") {
+        Some(Value::Array(features)) => {
+            let features: Option<Vec<&str>> = features.iter().map(|feature| feature.as_str()).collect();
+            let features = features.ok_or_else(|| ProtocolError::invalid("features", "Expected features as an array of strings"))?;
+            self::features(version).into_iter().filter(|feature| features.contains(feature)).collect()
+        }
+        Some(_) => return Err(ProtocolError::invalid("features", "Expected features as an array of strings")),
+        None => self::features(version),
+    };
+    Ok((version, features))
+}
+
+// the features a client speaking the version gets unless it asks for fewer
+pub fn features(version: u64) -> Vec<&'static str> {
+    FEATURES.iter().filter(|(_, since)| *since <= version).map(|(feature, _)| *feature).collect()
+}
+
+// strip what a client didn't negotiate from a message about to go to it, None if it shouldn't get the message at all
+// only changes other clients made are rewritten, responses to the client's own messages already fit what it negotiated
+pub fn downgrade(features: &[&str], mut message: Value) -> Option<Value> {
+    let broadcast = match message.get("type").and_then(|kind| kind.as_str()) {
+        Some(kind) if message.get("status") == Some(&json!("ok")) => kind.to_string(),
+        _ => String::new(),
+    };
+    match broadcast.as_str() {
+        // patches carry the patched value for clients that can't apply them, who get an update instead
+        "patch" if !features.contains(&"patch") => message = patched(&message)?,
+        "patch" => {
+            if let Some(message) = message.as_object_mut() {
+                message.remove("value");
+                message.remove("versions");
+            }
+        }
+        "delete" | "schema" if !features.contains(&broadcast.as_str()) => return None,
+        _ => {}
+    }
+    if !features.contains(&"schema") {
+        if let Some(message) = message.as_object_mut() {
+            message.remove("schema");
+        }
+    }
+    if !features.contains(&"versions") {
+        let versioned = matches!(message.get("type").and_then(|kind| kind.as_str()), Some("patch" | "ack"));
+        if let Some(message) = message.as_object_mut() {
+            message.remove("versions");
+            if versioned {
+                message.remove("version");
+            }
+        }
+    }
+    if !features.contains(&"binary") {
+        untag(&mut message);
+    }
+    Some(message)
+}
+
+// a published patch as the update setting the key to its patched value, if it carries one
+pub fn patched(message: &Value) -> Option<Value> {
+    let key = message.get("key")?.as_str()?;
+    match (message.get("value"), message.get("versions")) {
+        (Some(value), Some(versions)) if !value.is_null() => Some(json!({
+            "type": "update",
+            "status": "ok",
+            "versions": versions,
+            "table": {key: value}
+        })),
+        _ => None,
+    }
+}
+
+// why a client message was rejected, sent back as {"type": ..., "status": "error", "code": ..., "message": ...}
+// codes are stable so clients can act on them, messages are for people and may change
+#[derive(Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    // a message that isn't valid JSON, the connection carries on with the next one
+    InvalidJson,
+    // a frame that can't be read, e.g. one over the size limit, which ends the connection
+    InvalidFrame,
+    // a required field is missing or has the wrong type, named in "field"
+    InvalidMessage,
+    UnknownType,
+    HandshakeRequired,
+    HandshakeCompleted,
+    InvalidName,
+    AuthenticationFailed,
+    LockedOut,
+    CertificateMismatch,
+    UnsupportedFraming,
+    // no protocol version both the client and server speak
+    UnsupportedVersion,
+    // the client sent nothing, not even a pong, for longer than the idle timeout
+    IdleTimeout,
+    PermissionDenied,
+    NotFound,
+    // a path runs into a value that isn't an object
+    InvalidPath,
+    // a value doesn't match its key's schema
+    InvalidValue,
+    InvalidSchema,
+    SchemaLocked,
+    VersionConflict,
+    PatchFailed,
+}
+
+impl ErrorCode {
+    pub const ALL: &'static [ErrorCode] = &[
+        ErrorCode::InvalidJson, ErrorCode::InvalidFrame, ErrorCode::InvalidMessage, ErrorCode::UnknownType,
+        ErrorCode::HandshakeRequired, ErrorCode::HandshakeCompleted, ErrorCode::InvalidName, ErrorCode::AuthenticationFailed,
+        ErrorCode::LockedOut, ErrorCode::CertificateMismatch, ErrorCode::UnsupportedFraming, ErrorCode::UnsupportedVersion,
+        ErrorCode::IdleTimeout, ErrorCode::PermissionDenied, ErrorCode::NotFound, ErrorCode::InvalidPath, ErrorCode::InvalidValue,
+        ErrorCode::InvalidSchema, ErrorCode::SchemaLocked, ErrorCode::VersionConflict, ErrorCode::PatchFailed,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidJson => "invalid_json",
+            ErrorCode::InvalidFrame => "invalid_frame",
+            ErrorCode::InvalidMessage => "invalid_message",
+            ErrorCode::UnknownType => "unknown_type",
+            ErrorCode::HandshakeRequired => "handshake_required",
+            ErrorCode::HandshakeCompleted => "handshake_completed",
+            ErrorCode::InvalidName => "invalid_name",
+            ErrorCode::AuthenticationFailed => "authentication_failed",
+            ErrorCode::LockedOut => "locked_out",
+            ErrorCode::CertificateMismatch => "certificate_mismatch",
+            ErrorCode::UnsupportedFraming => "unsupported_framing",
+            ErrorCode::UnsupportedVersion => "unsupported_version",
+            ErrorCode::IdleTimeout => "idle_timeout",
+            ErrorCode::PermissionDenied => "permission_denied",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::InvalidPath => "invalid_path",
+            ErrorCode::InvalidValue => "invalid_value",
+            ErrorCode::InvalidSchema => "invalid_schema",
+            ErrorCode::SchemaLocked => "schema_locked",
+            ErrorCode::VersionConflict => "version_conflict",
+            ErrorCode::PatchFailed => "patch_failed",
+        }
+    }
+}
+
+pub struct ProtocolError {
+    pub code: ErrorCode,
+    pub message: String,
+    // anything else the client should know, e.g. the key at fault
+    fields: Map<String, Value>,
+}
+
+impl ProtocolError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self { code, message: message.into(), fields: Map::new() }
+    }
+
+    // a missing or mistyped field
+    pub fn invalid(field: &str, message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::InvalidMessage, message).with("field", field)
+    }
+
+    // a key the client isn't allowed to read or write, as the request needs
+    pub fn permission_denied(key: &str) -> Self {
+        Self::new(ErrorCode::PermissionDenied, format!("Permission denied for key: {}", key)).with("key", key)
+    }
+
+    pub fn with(mut self, field: &str, value: impl Into<Value>) -> Self {
+        self.fields.insert(field.to_string(), value.into());
+        self
+    }
+
+    // the error response to a message of the given type, echoing its id if it had one
+    // errors that can't be tied to a message type, like unparseable JSON, have the type "error"
+    pub fn to_json(&self, request_type: &str, id: Option<&Value>) -> Value {
+        let mut response = json!({
+            "type": request_type,
+            "status": "error",
+            "code": self.code.name(),
+            "message": self.message
+        });
+        if let Some(id) = id {
+            response["id"] = id.clone();
+        }
+        for (field, value) in &self.fields {
+            response[field] = value.clone();
+        }
+        response
+    }
+}
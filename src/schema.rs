@@ -0,0 +1,269 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use serde_json::{json, Value};
+use crate::path::SEPARATOR;
+use crate::utils::{json_equal, Data, JSON};
+
+// what a key's value has to look like, declared in the config file or by clients
+// e.g. {"type": "number", "min": 0, "max": 10, "units": "m/s", "description": "Top speed"}
+#[derive(Clone, Default)]
+pub struct Schema {
+    kind: Option<Kind>,
+    // bounds only apply to numbers
+    min: Option<f64>,
+    max: Option<f64>,
+    // the only values allowed, if set
+    values: Option<Vec<Value>>,
+    // units and description are for display only
+    units: Option<String>,
+    description: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    String,
+    // any number, including integers and NaN or infinite floats
+    Number,
+    Integer,
+    Bytes,
+    Boolean,
+    Array,
+    Object,
+}
+
+impl Kind {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "string" => Some(Kind::String),
+            "number" => Some(Kind::Number),
+            "integer" => Some(Kind::Integer),
+            "bytes" => Some(Kind::Bytes),
+            "boolean" => Some(Kind::Boolean),
+            "array" => Some(Kind::Array),
+            "object" => Some(Kind::Object),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::String => "string",
+            Kind::Number => "number",
+            Kind::Integer => "integer",
+            Kind::Bytes => "bytes",
+            Kind::Boolean => "boolean",
+            Kind::Array => "array",
+            Kind::Object => "object",
+        }
+    }
+
+    fn matches(&self, value: &Value) -> bool {
+        // NaN, infinities and bytes are written as objects, so go by what the value will be stored as
+        let data = match value {
+            Value::Object(object) if object.len() == 1 => Data::from_json(value),
+            _ => Data::None,
+        };
+        match self {
+            Kind::String => value.is_string(),
+            Kind::Number => value.is_number() || matches!(data, Data::Float(_)),
+            Kind::Integer => value.is_i64() || value.is_u64(),
+            Kind::Bytes => matches!(data, Data::Bytes(_)),
+            Kind::Boolean => value.is_boolean(),
+            Kind::Array => value.is_array(),
+            Kind::Object => value.is_object() && !matches!(data, Data::Float(_) | Data::Bytes(_)),
+        }
+    }
+}
+
+impl Schema {
+    pub fn from_json(json: &Value) -> Result<Self, String> {
+        let fields = json.as_object().ok_or("Expected an object")?;
+        let string = |name: &str, value: &Value| value.as_str().map(|value| value.to_string()).ok_or(format!("Expected {} to be a string", name));
+        let number = |name: &str, value: &Value| value.as_f64().ok_or(format!("Expected {} to be a number", name));
+
+        let mut schema = Schema::default();
+        for (name, value) in fields {
+            match name.as_str() {
+                "type" => schema.kind = Some(Kind::from_name(&string(name, value)?).ok_or(format!("Unknown type: {}", value))?),
+                "min" => schema.min = Some(number(name, value)?),
+                "max" => schema.max = Some(number(name, value)?),
+                "enum" => schema.values = Some(value.as_array().ok_or("Expected enum to be an array")?.clone()),
+                "units" => schema.units = Some(string(name, value)?),
+                "description" => schema.description = Some(string(name, value)?),
+                _ => return Err(format!("Unknown schema field: {}", name)),
+            }
+        }
+
+        if (schema.min.is_some() || schema.max.is_some()) && schema.kind.is_some_and(|kind| kind != Kind::Number && kind != Kind::Integer) {
+            return Err("min and max only apply to numbers".to_string());
+        }
+        if let (Some(min), Some(max)) = (schema.min, schema.max) {
+            if min > max {
+                return Err("min is greater than max".to_string());
+            }
+        }
+        Ok(schema)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut json = serde_json::Map::new();
+        if let Some(kind) = self.kind {
+            json.insert("type".to_string(), json!(kind.name()));
+        }
+        if let Some(min) = self.min {
+            json.insert("min".to_string(), json!(min));
+        }
+        if let Some(max) = self.max {
+            json.insert("max".to_string(), json!(max));
+        }
+        if let Some(values) = &self.values {
+            json.insert("enum".to_string(), json!(values));
+        }
+        if let Some(units) = &self.units {
+            json.insert("units".to_string(), json!(units));
+        }
+        if let Some(description) = &self.description {
+            json.insert("description".to_string(), json!(description));
+        }
+        Value::Object(json)
+    }
+
+    // the reason a value doesn't conform, if it doesn't
+    pub fn check(&self, value: &Value) -> Result<(), String> {
+        if let Some(kind) = self.kind {
+            if !kind.matches(value) {
+                return Err(format!("must be of type {}", kind.name()));
+            }
+        }
+        if let Some(number) = number(value) {
+            // NaN compares false against everything, so it would pass any bound
+            if number.is_nan() && (self.min.is_some() || self.max.is_some()) {
+                return Err("must not be NaN".to_string());
+            }
+            if self.min.is_some_and(|min| number < min) {
+                return Err(format!("must be at least {}", self.min.unwrap()));
+            }
+            if self.max.is_some_and(|max| number > max) {
+                return Err(format!("must be at most {}", self.max.unwrap()));
+            }
+        }
+        if let Some(values) = &self.values {
+            if !values.iter().any(|allowed| json_equal(allowed, value)) {
+                let values: Vec<String> = values.iter().map(|value| value.to_string()).collect();
+                return Err(format!("must be one of {}", values.join(", ")));
+            }
+        }
+        Ok(())
+    }
+}
+
+// the value of a number, including NaN and infinities written as {"$float": ...}
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(number) => number.as_f64(),
+        Value::Object(object) if object.len() == 1 => match Data::from_json(value) {
+            Data::Float(number) => Some(number),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+struct Entry {
+    schema: Schema,
+    // schemas from the config file can't be replaced by clients
+    fixed: bool,
+}
+
+// every declared schema, keyed by the path of the value it applies to
+//
+// callers check values while holding the table lock, and declaring a schema checks the current value under it too,
+// so a value never changes between being checked and being stored
+pub struct Schemas {
+    entries: Mutex<BTreeMap<String, Entry>>,
+}
+
+impl Schemas {
+    pub fn new(config: &BTreeMap<String, Schema>) -> Self {
+        let entries = config.iter()
+            .map(|(key, schema)| (key.clone(), Entry { schema: schema.clone(), fixed: true }))
+            .collect();
+        Schemas { entries: Mutex::new(entries) }
+    }
+
+    // check a value about to be stored at the key, including any schemas for paths inside it
+    pub fn check(&self, key: &str, value: &Value) -> Result<(), String> {
+        for (path, entry) in self.entries.lock().unwrap().iter() {
+            let value = match path.strip_prefix(key) {
+                Some("") => Some(value),
+                Some(rest) => match rest.strip_prefix(SEPARATOR) {
+                    Some(rest) => rest.split(SEPARATOR).try_fold(value, |value, field| value.get(field)),
+                    None => None,
+                },
+                None => None,
+            };
+            if let Some(value) = value {
+                entry.schema.check(value).map_err(|e| format!("Invalid value for {}: {}", path, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    // check values about to replace top-level keys, returning why each one that doesn't match fails
+    pub fn check_all<'a>(&self, values: impl Iterator<Item = (&'a String, Value)>) -> Vec<String> {
+        values.filter_map(|(key, value)| self.check(key, &value).err()).collect()
+    }
+
+    // whether a client may declare or remove the schema for a key
+    pub fn can_declare(&self, key: &str) -> Result<(), String> {
+        match self.entries.lock().unwrap().get(key) {
+            Some(entry) if entry.fixed => Err(format!("Schema for {} is set by the server", key)),
+            _ => Ok(()),
+        }
+    }
+
+    // declare a schema for a key, or remove it with None
+    pub fn declare(&self, key: &str, schema: Option<Schema>) {
+        let mut entries = self.entries.lock().unwrap();
+        match schema {
+            Some(schema) => entries.insert(key.to_string(), Entry { schema, fixed: false }),
+            None => entries.remove(key),
+        };
+    }
+
+    // the schemas of keys passing the filter, as sent in the handshake
+    pub fn to_json(&self, filter: impl Fn(&str) -> bool) -> Value {
+        Value::Object(self.entries.lock().unwrap().iter()
+            .filter(|(key, _)| filter(key))
+            .map(|(key, entry)| (key.clone(), entry.schema.to_json()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds_apply_to_tagged_floats() {
+        let schema = Schema::from_json(&json!({"type": "number", "min": 0, "max": 10})).unwrap();
+        assert!(schema.check(&json!(10)).is_ok());
+        assert!(schema.check(&json!(10.5)).is_err());
+        assert!(schema.check(&json!({"$float": "Infinity"})).is_err());
+        assert!(schema.check(&json!({"$float": "-Infinity"})).is_err());
+        assert!(schema.check(&json!({"$float": "NaN"})).is_err());
+
+        // without bounds any float is a number
+        let schema = Schema::from_json(&json!({"type": "number"})).unwrap();
+        assert!(schema.check(&json!({"$float": "NaN"})).is_ok());
+        assert!(schema.check(&json!({"$float": "Infinity"})).is_ok());
+    }
+
+    #[test]
+    fn enum_compares_numbers_by_value() {
+        let schema = Schema::from_json(&json!({"enum": [1, 2.5, [3]]})).unwrap();
+        assert!(schema.check(&json!(1.0)).is_ok());
+        assert!(schema.check(&json!([3.0])).is_ok());
+        assert!(schema.check(&json!(2)).is_err());
+    }
+}
@@ -6,80 +6,133 @@
 // - send messages
 
 use std::collections::HashMap;
-use std::net::{IpAddr, SocketAddr, TcpListener};
-use std::process::exit;
-use std::str::FromStr;
+use std::io::Error;
+use std::net::{SocketAddr, TcpListener};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use rustls::ServerConfig;
 use serde_json::Value;
-use crate::connection::Connection;
+use crate::auth::Auth;
+use crate::config::Config;
+use crate::bus::Bus;
+use crate::connection::{Connection, Context, Transport};
+use crate::journal::Journal;
+use crate::logger::{log_error, log_info};
+use crate::schema::Schemas;
+use crate::tls;
 use crate::utils::Data;
+use crate::versions::Versions;
 
 pub struct Server {
-    pub address: SocketAddr,
+    pub config: Arc<Config>,
     pub connections: Arc<Mutex<HashMap<usize, Connection>>>,
     pub table: Arc<Mutex<HashMap<String, Data>>>,
+    pub journal: Option<Arc<Journal>>,
+    pub auth: Arc<Auth>,
+    pub bus: Arc<Bus>,
+    pub schemas: Arc<Schemas>,
+    pub versions: Arc<Versions>,
+    // shared between the listeners so tcp and websocket clients never get the same id
+    next_id: Arc<AtomicUsize>,
 }
 
 impl Server {
+    #[allow(dead_code)]
     pub fn new() -> Self {
-        let address = SocketAddr::from(([127, 0, 0, 1], 8080));
-        let connections = Arc::new(Mutex::new(HashMap::new()));
-        let table = Arc::new(Mutex::new(HashMap::new()));
-        Self {
-            address,
-            connections,
-            table,
-        }
+        Self::with_config(Config::default())
     }
     
-    pub fn with_address(ip: &str, port: u16) -> Self {
-        let address = SocketAddr::new(IpAddr::from_str(ip).unwrap(), port);
+    pub fn with_config(config: Config) -> Self {
         let connections = Arc::new(Mutex::new(HashMap::new()));
-        let table = Arc::new(Mutex::new(HashMap::new()));
+        let table = Arc::new(Mutex::new(config.table.clone()));
+        let auth = Arc::new(Auth::from_config(&config));
+        let schemas = Arc::new(Schemas::new(&config.schema));
         Self {
-            address,
+            config: Arc::new(config),
             connections,
             table,
+            journal: None,
+            auth,
+            bus: Arc::new(Bus::default()),
+            schemas,
+            versions: Arc::new(Versions::default()),
+            next_id: Arc::new(AtomicUsize::new(0)),
         }
     }
 
-    pub fn start(&self) {
-        let listener = 
-            match TcpListener::bind(self.address) {
-                Ok(listener) => {
-                    println!("Server started on {}", self.address);
-                    listener
-                },
-                Err(e) => {
-                    println!("Failed to bind: {}", e);
-                    println!("Press enter to exit...");
-                    let mut input = String::new();
-                    std::io::stdin().read_line(&mut input).unwrap();
-                    exit(0);
-                }
-            };
-        let connections = self.connections.clone();
-        let table = self.table.clone();
+    pub fn start(&self) -> Result<(), Error> {
+        let tls = tls::server_config(&self.config)?;
+        let scheme = |plain, secure| if tls.is_some() { secure } else { plain };
+        
+        // bind everything before accepting so a bad address doesn't leave one transport running
+        let listener = match self.config.tcp {
+            true => Some(Server::bind(self.config.address)?),
+            false => None,
+        };
+        let ws_listener = match self.config.websocket {
+            true => Some(Server::bind(self.config.ws_address)?),
+            false => None,
+        };
+        
+        if let Some(listener) = listener {
+            log_info!("Server started on {}{}", self.config.address, scheme("", " (TLS)"));
+            self.accept(listener, Transport::Tcp, tls.clone());
+        }
+        if let Some(ws_listener) = ws_listener {
+            log_info!("WebSocket server started on {}://{}", scheme("ws", "wss"), self.config.ws_address);
+            self.accept(ws_listener, Transport::WebSocket, tls);
+        }
+        
+        Ok(())
+    }
+    
+    fn bind(address: SocketAddr) -> Result<TcpListener, Error> {
+        TcpListener::bind(address).map_err(|e| Error::new(e.kind(), format!("Failed to bind {}: {}", address, e)))
+    }
+    
+    fn accept(&self, listener: TcpListener, transport: Transport, tls: Option<Arc<ServerConfig>>) {
+        let context = Context {
+            table: self.table.clone(),
+            connections: self.connections.clone(),
+            journal: self.journal.clone(),
+            auth: self.auth.clone(),
+            bus: self.bus.clone(),
+            schemas: self.schemas.clone(),
+            versions: self.versions.clone(),
+            heartbeat: seconds(self.config.heartbeat),
+            idle_timeout: seconds(self.config.idle_timeout),
+        };
+        let next_id = self.next_id.clone();
         
         // spawn a new thread to accept new connections
         std::thread::spawn(move || {
-            for (id, stream) in listener.incoming().enumerate() {
-                let mut connection = Connection::new(id);
-
+            for stream in listener.incoming() {
                 match stream {
                     Ok(stream) => {
-                        connection.watch(&mut stream.try_clone().unwrap(), table.clone(), connections.clone());
+                        let id = next_id.fetch_add(1, Ordering::SeqCst);
+                        let (mut connection, poll) = match Connection::new(id, transport, tls.is_some()) {
+                            Ok(connection) => connection,
+                            Err(e) => {
+                                log_error!("Failed to set up connection: {}", e);
+                                continue;
+                            }
+                        };
+                        
+                        // hold the lock until the connection is registered so its thread can't remove it first
+                        let mut connections_guard = context.connections.lock().unwrap();
+                        connection.watch(stream, poll, tls.clone(), context.clone());
+                        connections_guard.insert(id, connection);
                     }
                     Err(e) => {
-                        println!("Failed to accept connection: {}", e);
+                        log_error!("Failed to accept connection: {}", e);
                     }
                 }
-
-                connections.lock().unwrap().insert(id, connection);
             }
         });
     }
     
+    #[allow(dead_code)]
     pub fn send(&self, id: usize, value: Value) {
         let mut connections = self.connections.lock().unwrap();
         if let Some(connection) = connections.get_mut(&id) {
@@ -87,6 +140,7 @@ impl Server {
         }
     }
     
+    #[allow(dead_code)]
     pub fn broadcast(&self, value: Value) {
         let mut connections = self.connections.lock().unwrap();
         for connection in connections.values_mut() {
@@ -94,6 +148,7 @@ impl Server {
         }
     }
     
+    #[allow(dead_code)]
     pub fn display(&self, clear: bool) {
         let connections = self.connections.lock().unwrap();
         let table = self.table.lock().unwrap();
@@ -102,11 +157,12 @@ impl Server {
             crate::utils::clear_lines(100);
         }
         println!("----- INFO ------");
-        println!("Address: {}", self.address);
+        println!("Address: {}", self.config.address);
+        println!("WebSocket: {}", self.config.ws_address);
         
         // display the connections
         println!("Connections: {}", connections.len());
-        for (_, connection) in &*connections {
+        for connection in connections.values() {
             connection.display();
         }
         
@@ -117,4 +173,9 @@ impl Server {
         }
         println!("-----------------");
     }
-}
\ No newline at end of file
+}
+
+// a setting in seconds where 0 turns it off
+fn seconds(seconds: u64) -> Option<Duration> {
+    (seconds > 0).then(|| Duration::from_secs(seconds))
+}
@@ -0,0 +1,146 @@
+use std::io::{Error, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use socket2::{SockRef, TcpKeepalive};
+use crate::tls;
+
+// a client socket, either plain or wrapped in TLS
+// everything above this reads and writes the same way regardless of which it is
+//
+// a connection starts out on a blocking std socket for the TLS handshake and HTTP upgrade,
+// then moves to a mio socket so its thread can sleep until there is something to do
+pub enum Stream<S: Socket = TcpStream> {
+    Plain(S),
+    Tls(Box<StreamOwned<ServerConnection, S>>),
+}
+
+pub type EventedStream = Stream<mio::net::TcpStream>;
+
+// the socket types a stream can sit on
+pub trait Socket: Read + Write {
+    fn shutdown(&self, how: Shutdown) -> Result<(), Error>;
+}
+
+impl Socket for TcpStream {
+    fn shutdown(&self, how: Shutdown) -> Result<(), Error> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+impl Socket for mio::net::TcpStream {
+    fn shutdown(&self, how: Shutdown) -> Result<(), Error> {
+        mio::net::TcpStream::shutdown(self, how)
+    }
+}
+
+impl Stream {
+    // run the TLS handshake on a freshly accepted socket, giving up on clients that stall
+    pub fn accept_tls(mut socket: TcpStream, config: Arc<ServerConfig>) -> Result<Self, Error> {
+        let mut connection = ServerConnection::new(config).map_err(Error::other)?;
+
+        socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut socket)?;
+        }
+        socket.set_read_timeout(None)?;
+
+        Ok(Stream::Tls(Box::new(StreamOwned::new(connection, socket))))
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.socket().set_read_timeout(timeout)
+    }
+
+    // switch to a nonblocking socket that can be registered with a mio poll
+    pub fn into_evented(self) -> Result<EventedStream, Error> {
+        let evented = |socket: TcpStream| -> Result<mio::net::TcpStream, Error> {
+            socket.set_nonblocking(true)?;
+            Ok(mio::net::TcpStream::from_std(socket))
+        };
+
+        Ok(match self {
+            Stream::Plain(socket) => Stream::Plain(evented(socket)?),
+            Stream::Tls(stream) => {
+                let (connection, socket) = stream.into_parts();
+                Stream::Tls(Box::new(StreamOwned::new(connection, evented(socket)?)))
+            }
+        })
+    }
+}
+
+impl EventedStream {
+    // have the OS probe a quiet connection after `idle`, then every `interval`, and drop it after `retries` go unanswered,
+    // which notices a peer that vanished without closing the connection without the client having to do anything
+    pub fn set_keepalive(&self, idle: Duration, interval: Duration, retries: u32) -> Result<(), Error> {
+        let keepalive = TcpKeepalive::new().with_time(idle).with_interval(interval).with_retries(retries);
+        SockRef::from(self.socket()).set_tcp_keepalive(&keepalive)
+    }
+}
+
+impl<S: Socket> Stream<S> {
+    fn socket(&self) -> &S {
+        match self {
+            Stream::Plain(socket) => socket,
+            Stream::Tls(stream) => &stream.sock,
+        }
+    }
+
+    pub fn socket_mut(&mut self) -> &mut S {
+        match self {
+            Stream::Plain(socket) => socket,
+            Stream::Tls(stream) => &mut stream.sock,
+        }
+    }
+
+    // the common name of the client certificate, if the client presented one
+    pub fn peer_identity(&self) -> Option<String> {
+        match self {
+            Stream::Plain(_) => None,
+            Stream::Tls(stream) => tls::common_name(stream.conn.peer_certificates()?.first()?),
+        }
+    }
+
+    // whether TLS records are still waiting to go out on the socket
+    pub fn wants_write(&self) -> bool {
+        match self {
+            Stream::Plain(_) => false,
+            Stream::Tls(stream) => stream.conn.wants_write(),
+        }
+    }
+
+    // tell a TLS client the session is over before closing the socket
+    pub fn shutdown(&mut self, how: Shutdown) -> Result<(), Error> {
+        if let Stream::Tls(stream) = self {
+            stream.conn.send_close_notify();
+            let _ = stream.conn.complete_io(&mut stream.sock);
+        }
+        self.socket().shutdown(how)
+    }
+}
+
+impl<S: Socket> Read for Stream<S> {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            Stream::Plain(socket) => socket.read(buffer),
+            Stream::Tls(stream) => stream.read(buffer),
+        }
+    }
+}
+
+impl<S: Socket> Write for Stream<S> {
+    fn write(&mut self, buffer: &[u8]) -> Result<usize, Error> {
+        match self {
+            Stream::Plain(socket) => socket.write(buffer),
+            Stream::Tls(stream) => stream.write(buffer),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self {
+            Stream::Plain(socket) => socket.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::Arc;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use crate::config::Config;
+
+// object identifier of the common name attribute, 2.5.4.3
+const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+// build the TLS settings shared by every listener, or None if no certificate is configured
+pub fn server_config(config: &Config) -> Result<Option<Arc<ServerConfig>>, Error> {
+    let (cert, key) = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return Ok(None),
+    };
+
+    let certs = load_certs(cert)?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Failed to load TLS key {}: {}", key.display(), e)))?;
+
+    // client certificates are optional unless required, clients without one authenticate as usual
+    let builder = match &config.tls_client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca)? {
+                roots.add(cert).map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid client CA {}: {}", client_ca.display(), e)))?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+            let verifier = match config.tls_require_client_cert {
+                true => verifier.build(),
+                false => verifier.allow_unauthenticated().build(),
+            };
+            ServerConfig::builder().with_client_cert_verifier(verifier.map_err(Error::other)?)
+        }
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+
+    let server_config = builder.with_single_cert(certs, key)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid TLS certificate {}: {}", cert.display(), e)))?;
+    Ok(Some(Arc::new(server_config)))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Failed to load certificates from {}: {}", path.display(), e)))?;
+
+    if certs.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, format!("No certificates found in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+// pull the subject common name out of a DER certificate
+// the certificate has already been verified, this only walks far enough to find the subject:
+// Certificate -> TBSCertificate -> [version], serial, signature, issuer, validity, subject
+pub fn common_name(cert: &CertificateDer) -> Option<String> {
+    let (_, certificate, _) = read_element(cert)?;
+    let (_, mut tbs, _) = read_element(certificate)?;
+
+    // the version is an optional explicitly tagged field
+    if tbs.first() == Some(&0xA0) {
+        tbs = read_element(tbs)?.2;
+    }
+    // serial number, signature algorithm, issuer and validity
+    for _ in 0..4 {
+        tbs = read_element(tbs)?.2;
+    }
+    let (_, mut subject, _) = read_element(tbs)?;
+
+    // the subject is a sequence of sets of (type, value) pairs
+    while !subject.is_empty() {
+        let (_, mut set, rest) = read_element(subject)?;
+        subject = rest;
+
+        while !set.is_empty() {
+            let (_, attribute, rest) = read_element(set)?;
+            set = rest;
+
+            let (tag, oid, value) = read_element(attribute)?;
+            if tag == 0x06 && oid == COMMON_NAME {
+                let (_, value, _) = read_element(value)?;
+                return String::from_utf8(value.to_vec()).ok();
+            }
+        }
+    }
+
+    None
+}
+
+// split one DER element off the front of the data, returning its tag, contents and whatever follows it
+fn read_element(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.first()?;
+    let first = *data.get(1)? as usize;
+
+    let (length, header) = if first < 0x80 {
+        (first, 2)
+    } else {
+        // long form, the low bits give the number of length bytes that follow
+        let count = first & 0x7F;
+        if count == 0 || count > 4 {
+            return None;
+        }
+        let bytes = data.get(2..2 + count)?;
+        (bytes.iter().fold(0, |length, byte| (length << 8) | *byte as usize), 2 + count)
+    };
+
+    let contents = data.get(header..header.checked_add(length)?)?;
+    Some((tag, contents, &data[header + length..]))
+}
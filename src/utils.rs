@@ -1,10 +1,21 @@
 use std::collections::HashMap;
 use std::fmt::Display;
+use base64::Engine;
 use serde_json::Value;
 
+// JSON has no way to write NaN or infinite floats, or raw bytes, so they are sent as single field objects:
+// {"$float": "NaN"}, {"$float": "Infinity"}, {"$float": "-Infinity"} and {"$bytes": "<base64>"}
+const FLOAT_TAG: &str = "$float";
+const BYTES_TAG: &str = "$bytes";
+
+#[derive(Clone)]
 pub enum Data {
     String(String),
-    Number(f64),
+    Integer(i64),
+    // only for integers too large for an i64
+    Unsigned(u64),
+    Float(f64),
+    Bytes(Vec<u8>),
     Boolean(bool),
     Array(Vec<Data>),
     Object(HashMap<String, Data>),
@@ -15,7 +26,16 @@ impl Display for Data {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Data::String(s) => write!(f, "{}", s),
-            Data::Number(n) => write!(f, "{}", n),
+            Data::Integer(i) => write!(f, "{}", i),
+            Data::Unsigned(u) => write!(f, "{}", u),
+            Data::Float(n) => write!(f, "{:?}", n),
+            Data::Bytes(b) => {
+                write!(f, "0x")?;
+                for byte in b {
+                    write!(f, "{:02x}", byte)?;
+                }
+                Ok(())
+            }
             Data::Boolean(b) => write!(f, "{}", b),
             Data::Array(a) => {
                 write!(f, "[")?;
@@ -42,6 +62,7 @@ impl Display for Data {
     }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 pub trait JSON {
     fn to_json(&self) -> serde_json::Value;
     fn from_json(value: &serde_json::Value) -> Self;
@@ -51,7 +72,16 @@ impl JSON for Data {
     fn to_json(&self) -> Value {
         match self {
             Data::String(s) => Value::String(s.clone()),
-            Data::Number(n) => Value::Number(serde_json::Number::from_f64(*n).unwrap()),
+            Data::Integer(i) => Value::from(*i),
+            Data::Unsigned(u) => Value::from(*u),
+            Data::Float(n) => match serde_json::Number::from_f64(*n) {
+                Some(n) => Value::Number(n),
+                None => {
+                    let name = if n.is_nan() { "NaN" } else if *n > 0.0 { "Infinity" } else { "-Infinity" };
+                    serde_json::json!({ FLOAT_TAG: name })
+                }
+            },
+            Data::Bytes(b) => serde_json::json!({ BYTES_TAG: base64::engine::general_purpose::STANDARD.encode(b) }),
             Data::Boolean(b) => Value::Bool(*b),
             Data::Array(a) => {
                 let mut json_array = Vec::new();
@@ -74,7 +104,13 @@ impl JSON for Data {
     fn from_json(value: &Value) -> Self {
         match value {
             Value::String(s) => Data::String(s.clone()),
-            Value::Number(n) => Data::Number(n.as_f64().unwrap()),
+            // integers stay exact, anything written with a fraction or exponent is a float
+            Value::Number(n) => match (n.as_i64(), n.as_u64(), n.as_f64()) {
+                (Some(i), _, _) => Data::Integer(i),
+                (_, Some(u), _) => Data::Unsigned(u),
+                (_, _, Some(f)) => Data::Float(f),
+                _ => Data::None,
+            },
             Value::Bool(b) => Data::Boolean(*b),
             Value::Array(a) => {
                 let mut data_array = Vec::new();
@@ -83,6 +119,11 @@ impl JSON for Data {
                 }
                 Data::Array(data_array)
             }
+            Value::Object(o) if o.len() == 1 && (o.contains_key(FLOAT_TAG) || o.contains_key(BYTES_TAG)) => match tagged(o) {
+                Some(data) => data,
+                // not a valid encoding, so it is just an object that happens to look like one
+                None => Data::Object(o.iter().map(|(key, value)| (key.clone(), Data::from_json(value))).collect()),
+            },
             Value::Object(o) => {
                 let mut data_object = HashMap::new();
                 for (key, json_value) in o {
@@ -95,10 +136,165 @@ impl JSON for Data {
     }
 }
 
+fn tagged(object: &serde_json::Map<String, Value>) -> Option<Data> {
+    if let Some(name) = object.get(FLOAT_TAG) {
+        return match name.as_str()? {
+            "NaN" => Some(Data::Float(f64::NAN)),
+            "Infinity" => Some(Data::Float(f64::INFINITY)),
+            "-Infinity" => Some(Data::Float(f64::NEG_INFINITY)),
+            _ => None,
+        };
+    }
+    let encoded = object.get(BYTES_TAG)?.as_str()?;
+    base64::engine::general_purpose::STANDARD.decode(encoded).ok().map(Data::Bytes)
+}
+
+// rewrite the float and bytes encodings inside a value for clients that don't understand them,
+// NaN and infinities become null as plain JSON has no way to write them, and bytes become their base64 string
+pub fn untag(value: &mut Value) {
+    match value {
+        Value::Object(object) if object.len() == 1 && (object.contains_key(FLOAT_TAG) || object.contains_key(BYTES_TAG)) => {
+            match tagged(object) {
+                Some(Data::Float(_)) => *value = Value::Null,
+                Some(_) => *value = object.remove(BYTES_TAG).unwrap_or_default(),
+                None => object.values_mut().for_each(untag),
+            }
+        }
+        Value::Object(object) => object.values_mut().for_each(untag),
+        Value::Array(array) => array.iter_mut().for_each(untag),
+        _ => {}
+    }
+}
+
+// whether two JSON values are equal, with numbers compared by value so 1 and 1.0 are the same,
+// as JSON Patch tests and schema enums require
+pub fn json_equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => match (a.as_i64(), b.as_i64(), a.as_u64(), b.as_u64()) {
+            // integers are compared exactly, as large ones lose precision as floats
+            (Some(a), Some(b), _, _) => a == b,
+            (_, _, Some(a), Some(b)) => a == b,
+            _ => a.as_f64() == b.as_f64(),
+        },
+        (Value::Array(a), Value::Array(b)) => a.len() == b.len() && a.iter().zip(b).all(|(a, b)| json_equal(a, b)),
+        (Value::Object(a), Value::Object(b)) => a.len() == b.len() && a.iter().all(|(key, a)| b.get(key).is_some_and(|b| json_equal(a, b))),
+        _ => a == b,
+    }
+}
+
 pub fn clear_lines(n: u16) {
     println!("\x1b[{}A\x1b[J", n);
 }
 
 pub fn clear_screen() {
     print!("\x1B[2J\x1B[1;1H");
-}
\ No newline at end of file
+}
+
+pub fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// format milliseconds since the epoch as an ISO 8601 UTC timestamp, e.g. 2024-05-01T12:30:00.250Z
+pub fn format_time(millis: u64) -> String {
+    let seconds = millis / 1000;
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    let time = seconds % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, time / 3600, (time / 60) % 60, time % 60, millis % 1000
+    )
+}
+
+// parse either milliseconds since the epoch or an ISO 8601 UTC timestamp
+// the time of day and fractional seconds are optional: 2024-05-01, 2024-05-01T12:30, 2024-05-01T12:30:00.250Z
+pub fn parse_time(string: &str) -> Option<u64> {
+    if let Ok(millis) = string.parse::<u64>() {
+        return Some(millis);
+    }
+
+    let string = string.trim_end_matches('Z');
+    let (date, time) = match string.split_once(['T', ' ']) {
+        Some((date, time)) => (date, time),
+        None => (string, "00:00"),
+    };
+
+    let mut date = date.split('-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    if date.next().is_some() || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let (time, fraction) = match time.split_once('.') {
+        Some((time, fraction)) => (time, fraction),
+        None => (time, "0"),
+    };
+    let mut time = time.split(':').map(|part| part.parse::<u64>().ok());
+    let hours = time.next()??;
+    let minutes = time.next()??;
+    let seconds = time.next().unwrap_or(Some(0))?;
+    if time.next().is_some() || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+    // only millisecond precision is kept
+    let millis = format!("{:0<3}", fraction).get(..3)?.parse::<u64>().ok()?;
+
+    let days = days_from_civil(year, month, day);
+    if days < 0 {
+        return None;
+    }
+    Some(((days as u64 * 86400) + hours * 3600 + minutes * 60 + seconds) * 1000 + millis)
+}
+
+// days since 1970-01-01 for a proleptic gregorian date (Howard Hinnant's algorithm)
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// match a key against a glob pattern, where * matches any run of characters and ? matches one
+pub fn glob_match(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+
+    let (mut p, mut k) = (0, 0);
+    // where to resume if the most recent * needs to swallow another character
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while k < key.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == key[k]) {
+            p += 1;
+            k += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, k));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            k = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use serde_json::{json, Value};
+use crate::path;
+use crate::utils::Data;
+
+// the version of every top-level key and which client last wrote it, so clients can tell whether
+// a key changed since they read it and make updates conditional on it
+//
+// versions come from one counter shared by every key, so a key's version only ever goes up,
+// even if it is deleted and created again, and 0 means the key doesn't exist
+// like schemas, versions are only changed while the table lock is held
+#[derive(Default)]
+pub struct Versions {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    last: u64,
+    entries: HashMap<String, Version>,
+}
+
+#[derive(Clone, Copy)]
+pub struct Version {
+    pub version: u64,
+    // None for changes made by the server itself
+    pub writer: Option<usize>,
+}
+
+impl Versions {
+    // record a change at a path, after it has been applied to the table
+    // the top-level key it lives under gets a new version, or loses it if the key is gone
+    pub fn touch(&self, table: &HashMap<String, Data>, key: &str, writer: Option<usize>) {
+        let root = path::root(table, key);
+        let mut inner = self.inner.lock().unwrap();
+        if table.contains_key(root) {
+            inner.last += 1;
+            let version = Version { version: inner.last, writer };
+            inner.entries.insert(root.to_string(), version);
+        } else {
+            inner.entries.remove(root);
+        }
+    }
+
+    // give every key in the table a new version, for when it has been replaced wholesale
+    pub fn reset(&self, table: &HashMap<String, Data>, writer: Option<usize>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.entries.clear();
+        let mut keys: Vec<&String> = table.keys().collect();
+        keys.sort();
+        for key in keys {
+            inner.last += 1;
+            let version = Version { version: inner.last, writer };
+            inner.entries.insert(key.clone(), version);
+        }
+    }
+
+    // the version of the top-level key a path lives under, 0 if it doesn't exist
+    pub fn get(&self, table: &HashMap<String, Data>, key: &str) -> u64 {
+        let root = path::root(table, key);
+        self.inner.lock().unwrap().entries.get(root).map_or(0, |version| version.version)
+    }
+
+    // the version and last writer of each of the given keys, for responses and broadcasts
+    pub fn to_json<'a>(&self, table: &HashMap<String, Data>, keys: impl Iterator<Item = &'a String>) -> Value {
+        let inner = self.inner.lock().unwrap();
+        Value::Object(keys
+            .map(|key| {
+                let version = inner.entries.get(path::root(table, key));
+                (key.clone(), json!({
+                    "version": version.map_or(0, |version| version.version),
+                    "writer": version.and_then(|version| version.writer)
+                }))
+            })
+            .collect())
+    }
+}
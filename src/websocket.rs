@@ -0,0 +1,120 @@
+use std::io::{Error, ErrorKind, Write};
+use base64::Engine;
+use crate::http::Request;
+use crate::stream::Stream;
+
+// appended to the client's key when computing Sec-WebSocket-Accept (RFC 6455 section 1.3)
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub const OPCODE_CONTINUATION: u8 = 0x0;
+pub const OPCODE_TEXT: u8 = 0x1;
+pub const OPCODE_BINARY: u8 = 0x2;
+pub const OPCODE_CLOSE: u8 = 0x8;
+pub const OPCODE_PING: u8 = 0x9;
+pub const OPCODE_PONG: u8 = 0xA;
+
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+pub fn is_upgrade(request: &Request) -> bool {
+    request.method == "GET"
+        && request.header("upgrade").is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+        && request.header("sec-websocket-key").is_some()
+}
+
+// answer an upgrade request, after which the socket speaks websocket frames
+pub fn accept(stream: &mut Stream, request: &Request) -> Result<(), Error> {
+    let key = match request.header("sec-websocket-key") {
+        Some(key) => key,
+        None => return Err(Error::new(ErrorKind::InvalidData, "Missing Sec-WebSocket-Key")),
+    };
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    stream.write_all(response.as_bytes())
+}
+
+fn accept_key(key: &str) -> String {
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(ACCEPT_GUID.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(sha1.digest().bytes())
+}
+
+// server frames are never masked
+pub fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![0x80 | opcode];
+    let length = payload.len();
+    if length < 126 {
+        bytes.push(length as u8);
+    } else if length <= u16::MAX as usize {
+        bytes.push(126);
+        bytes.extend_from_slice(&(length as u16).to_be_bytes());
+    } else {
+        bytes.push(127);
+        bytes.extend_from_slice(&(length as u64).to_be_bytes());
+    }
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+// parse one frame from the start of the buffer
+// returns the frame and the number of bytes it used, or None if the frame hasn't fully arrived
+pub fn parse_frame(buffer: &[u8], max_size: usize) -> Result<Option<(Frame, usize)>, Error> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+
+    let fin = buffer[0] & 0x80 != 0;
+    let opcode = buffer[0] & 0x0F;
+    let masked = buffer[1] & 0x80 != 0;
+    let mut offset = 2;
+
+    // clients must mask every frame they send
+    if !masked {
+        return Err(Error::other("Received an unmasked websocket frame"));
+    }
+
+    let length = match buffer[1] & 0x7F {
+        126 => {
+            if buffer.len() < offset + 2 {
+                return Ok(None);
+            }
+            offset += 2;
+            u16::from_be_bytes([buffer[2], buffer[3]]) as usize
+        }
+        127 => {
+            if buffer.len() < offset + 8 {
+                return Ok(None);
+            }
+            let mut length = [0; 8];
+            length.copy_from_slice(&buffer[2..10]);
+            offset += 8;
+            u64::from_be_bytes(length) as usize
+        }
+        length => length as usize,
+    };
+
+    if length > max_size {
+        return Err(Error::other(format!("Websocket frame of {} bytes exceeds the maximum frame size", length)));
+    }
+    if buffer.len() < offset + 4 + length {
+        return Ok(None);
+    }
+
+    let mask = [buffer[offset], buffer[offset + 1], buffer[offset + 2], buffer[offset + 3]];
+    offset += 4;
+
+    let payload = buffer[offset..offset + length]
+        .iter()
+        .enumerate()
+        .map(|(i, b)| b ^ mask[i % 4])
+        .collect();
+
+    Ok(Some((Frame { fin, opcode, payload }, offset + length)))
+}